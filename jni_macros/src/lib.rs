@@ -0,0 +1,240 @@
+//! Procedural macros for the `jni` crate.
+//!
+//! `#[jni_export(class = "com.acme.Foo")]` exports a Rust function as the
+//! implementation of a native method of a Java class:
+//!
+//! ```ignore
+//! #[jni_export(class = "com.acme.Foo")]
+//! fn greet<'a>(env: &'a JavaEnv<'a>, _this: JavaObject<'a>, cap: Capability, name: JavaString<'a>) -> JniResult<JavaString<'a>> {
+//!     let name = name.to_str(&cap).unwrap();
+//!     JavaString::new(env, &format!("Hello, {}!", name), cap)
+//! }
+//! ```
+//!
+//! The first argument is the `JavaEnv`, the second one is the object (or
+//! the class, for static methods) the method is called on, and the rest
+//! are the arguments of the Java method.  An argument of type `Capability`
+//! may be put anywhere after the `JavaEnv`.  Arguments and the return value
+//! are converted with `JNativeArg` and `JNativeResult`.
+//!
+//! The Java method name defaults to the name of the Rust function and can
+//! be changed with `name = "..."`.  For overloaded methods, pass the method
+//! descriptor with `signature = "..."` to get the long symbol name.
+//!
+//! A panic in the function is thrown to Java as `java.lang.RuntimeException`,
+//! or as the class given with `panic = "..."`.  Returning `Err` from a
+//! function returning `Result<T, E>` throws the error with `ToJavaException`.
+
+extern crate proc_macro;
+extern crate proc_macro2;
+#[macro_use]
+extern crate quote;
+extern crate syn;
+
+use proc_macro::TokenStream;
+use proc_macro2::Span;
+use syn::{FnArg, Ident, ItemFn, LitStr, ReturnType, Type};
+use syn::visit_mut::VisitMut;
+
+#[proc_macro_attribute]
+pub fn jni_export(attr: TokenStream, item: TokenStream) -> TokenStream {
+	let mut class: Option<LitStr> = None;
+	let mut name: Option<LitStr> = None;
+	let mut sig: Option<LitStr> = None;
+	let mut panic_class: Option<LitStr> = None;
+	let parser = syn::meta::parser(|meta| {
+		if meta.path.is_ident("class") {
+			class = Some(meta.value()?.parse()?);
+		} else if meta.path.is_ident("name") {
+			name = Some(meta.value()?.parse()?);
+		} else if meta.path.is_ident("signature") {
+			sig = Some(meta.value()?.parse()?);
+		} else if meta.path.is_ident("panic") {
+			panic_class = Some(meta.value()?.parse()?);
+		} else {
+			return Err(meta.error("expected `class`, `name`, `signature` or `panic`"));
+		}
+		Ok(())
+	});
+	syn::parse_macro_input!(attr with parser);
+	let func = syn::parse_macro_input!(item as ItemFn);
+
+	let class = match class {
+		Some(class) => class.value(),
+		None => return syn::Error::new(Span::call_site(), "missing `class = \"...\"`").to_compile_error().into(),
+	};
+	let name = match name {
+		Some(name) => name.value(),
+		None => func.sig.ident.to_string(),
+	};
+	let sym = match sig {
+		None => symbol_name(&class, &name, None),
+		Some(sig) => match sig.value().find(')') {
+			Some(i) if sig.value().starts_with("(") => symbol_name(&class, &name, Some(&sig.value()[1..i])),
+			_ => return syn::Error::new_spanned(sig, "invalid method descriptor").to_compile_error().into(),
+		},
+	};
+
+	let panic_class = match panic_class {
+		Some(cls) => cls.value().replace('.', "/"),
+		None => "java/lang/RuntimeException".to_string(),
+	};
+
+	match export(&func, &sym, &panic_class) {
+		Ok(res) => res.into(),
+		Err(err) => err.to_compile_error().into(),
+	}
+}
+
+fn export(func: &ItemFn, sym: &str, panic_class: &str) -> syn::Result<proc_macro2::TokenStream> {
+	let fn_name = &func.sig.ident;
+	let sym = Ident::new(sym, Span::call_site());
+
+	let mut inputs = func.sig.inputs.iter();
+	match inputs.next() {
+		Some(&FnArg::Typed(_)) => (),
+		_ => return Err(syn::Error::new_spanned(&func.sig, "the first argument must be the `JavaEnv`")),
+	}
+
+	let mut params = vec![];
+	let mut call_args = vec![quote!(&env)];
+	let mut this = None;
+	let mut needs_cap = false;
+	for (i, input) in inputs.enumerate() {
+		let ty = match *input {
+			FnArg::Typed(ref arg) => &*arg.ty,
+			FnArg::Receiver(_) => return Err(syn::Error::new_spanned(input, "methods can't be exported")),
+		};
+		if is_capability(ty) {
+			needs_cap = true;
+			call_args.push(quote!(cap));
+		} else if this.is_none() {
+			this = Some(ty);
+			call_args.push(quote!(unsafe { ::jni::JNativeArg::from_native(&env, this) }));
+		} else {
+			let arg = Ident::new(&format!("arg{}", i), Span::call_site());
+			let ty = static_type(ty);
+			params.push(quote!(#arg: <#ty as ::jni::JNativeArg<'static>>::Raw));
+			call_args.push(quote!(unsafe { ::jni::JNativeArg::from_native(&env, #arg) }));
+		}
+	}
+	if this.is_none() {
+		return Err(syn::Error::new_spanned(&func.sig, "the second argument must be the object or the class"));
+	}
+
+	let ret = match func.sig.output {
+		ReturnType::Default => quote!(()),
+		ReturnType::Type(_, ref ty) => {
+			let ty = static_type(ty);
+			quote!(#ty)
+		}
+	};
+	let cap = if needs_cap {
+		quote! {
+			let cap = match ::jni::JavaThrowable::check(&env) {
+				Ok(cap) => cap,
+				Err(_) => panic!("Native method called with pending exception."),
+			};
+		}
+	} else {
+		quote!()
+	};
+
+	Ok(quote! {
+		#func
+
+		#[no_mangle]
+		#[allow(non_snake_case)]
+		#[doc(hidden)]
+		pub extern "system" fn #sym(env: *mut ::jni::native::JNIEnvImpl, this: ::jni::native::jobject, #(#params),*) -> <#ret as ::jni::JNativeResult>::Raw {
+			let env = match unsafe { ::jni::JavaEnv::from_raw(env) } {
+				Ok(env) => env,
+				// there is no `JavaEnv` to throw with
+				Err(_) => return ::jni::JNativeType::default_value(),
+			};
+			::jni::JavaThrowable::catch_panic(&env, #panic_class, || {
+				#cap
+				#fn_name(#(#call_args),*)
+			})
+		}
+	})
+}
+
+fn is_capability(ty: &Type) -> bool {
+	match *ty {
+		Type::Path(ref path) => match path.path.segments.last() {
+			Some(seg) => seg.ident == "Capability",
+			None => false,
+		},
+		_ => false,
+	}
+}
+
+struct StaticLifetimes;
+
+impl VisitMut for StaticLifetimes {
+	fn visit_lifetime_mut(&mut self, lifetime: &mut syn::Lifetime) {
+		lifetime.ident = Ident::new("static", lifetime.ident.span());
+	}
+}
+
+/// The raw types don't depend on lifetimes, so all of them are made `'static`
+/// to use the argument types outside of the function.
+fn static_type(ty: &Type) -> Type {
+	let mut ty = ty.clone();
+	StaticLifetimes.visit_type_mut(&mut ty);
+	ty
+}
+
+/// Builds the name of the symbol the JVM looks up for a native method.
+/// `args` is the argument part of the method descriptor, for overloaded methods.
+fn symbol_name(class: &str, method: &str, args: Option<&str>) -> String {
+	let mut res = format!("Java_{}_{}", mangle(class), mangle(method));
+	if let Some(args) = args {
+		res.push_str("__");
+		res.push_str(&mangle(args));
+	}
+	res
+}
+
+fn mangle(name: &str) -> String {
+	let mut res = String::with_capacity(name.len());
+	for c in name.chars() {
+		match c {
+			'.' | '/' => res.push('_'),
+			'_' => res.push_str("_1"),
+			';' => res.push_str("_2"),
+			'[' => res.push_str("_3"),
+			'a'..='z' | 'A'..='Z' | '0'..='9' => res.push(c),
+			_ => {
+				let mut buf = [0u16; 2];
+				for u in c.encode_utf16(&mut buf) {
+					res.push_str(&format!("_0{:04x}", u));
+				}
+			}
+		}
+	}
+	res
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_mangle() {
+		assert!(mangle("com.acme.Foo") == "com_acme_Foo");
+		assert!(mangle("com/acme/Foo") == "com_acme_Foo");
+		assert!(mangle("my_method") == "my_1method");
+		assert!(mangle("[Ljava/lang/String;") == "_3Ljava_lang_String_2");
+		assert!(mangle("caf\u{e9}") == "caf_000e9");
+		assert!(mangle("\u{1f600}") == "_0d83d_0de00");
+	}
+
+	#[test]
+	fn test_symbol_name() {
+		assert!(symbol_name("com.acme.Foo", "bar", None) == "Java_com_acme_Foo_bar");
+		assert!(symbol_name("com.acme.Foo_Bar", "baz_1", None) == "Java_com_acme_Foo_1Bar_baz_11");
+		assert!(symbol_name("p.q.r.A", "f", Some("ILjava/lang/String;[I")) == "Java_p_q_r_A_f__ILjava_lang_String_2_3I");
+	}
+}
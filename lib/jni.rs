@@ -3,12 +3,14 @@
 //! ## Handling of Java types
 //!
 //! * Java's primitive types, `String`, `Class`, and `Object` are
-//!   distinguished in this interface.  No distinction is made by this
-//!   library between other different Java classes.
+//!   distinguished in this interface.  Other classes are all wrapped
+//!   as `JavaObject`.
 //!
-//! * Method calls are dynamically checked: calls with wrong number of
-//!   arguments raise `IndexOutOfBoundsException`, and calls with
-//!   wrong types raise `ClassCastException`.
+//! * Method calls are dynamically checked: calls with wrong number or
+//!   types of arguments, or arguments of the wrong classes, raise
+//!   `IllegalArgumentException`, like `Method.invoke` does, and calls
+//!   with a result type the declared one can't be cast to raise
+//!   `ClassCastException`.  Fields are checked the same way.
 //!
 //! ## Exception handling
 //!
@@ -37,15 +39,36 @@
 //! undefined behavior due to unwinding outside of Rust code.  The
 //! solution is to throw a Java `RuntimeException` instead, as is the
 //! Java practice.  Note that this does lose Rust-level backtraces.
+//! `JavaThrowable::catch_panic` does this, and `#[jni_export]` runs
+//! every exported native method through it.  The first call installs a
+//! panic hook to get the location of the panic, which still calls the
+//! hook that was set before it.
 
 use ::std::mem;
+use ::std::ops::Deref;
 use ::std::fmt;
 use ::std::string;
 use std::ffi::{CString, CStr};
 use ::std::marker::PhantomData;
+use ::std::sync::{Arc, Once, OnceLock};
+use ::std::sync::atomic::{AtomicBool, Ordering};
+use ::std::sync::mpsc;
+use ::std::thread;
+use ::std::time::Duration;
+use ::std::cell::{Cell, RefCell};
+use ::std::panic::{self, AssertUnwindSafe};
+use ::std::any::Any;
+use ::std::error::Error;
+use ::std::io;
 
 use super::native::*;
+#[cfg(all(unix, feature = "dynamic-jvm"))]
+use super::jvm_library::{JvmLibrary, LoadError};
 use super::j_chars::JavaChars;
+use super::signature::{JavaType, JTyped};
+use super::ref_tracker::{self, LocalRefs};
+#[cfg(feature = "track-refs")]
+use super::ref_tracker::RefCounts;
 
 /// A token that indicates that the VM does not have a pending
 /// exception.
@@ -245,6 +268,91 @@ impl RPrimitive for f64 {
 	type JType = jdouble;
 }
 
+/// A reference to a Java object borrowed for passing it as a `JValue`.
+#[derive(Debug, Clone, Copy)]
+#[allow(raw_pointer_derive)]
+pub struct JObjectRef<'a> {
+	ptr: jobject,
+	phantom: PhantomData<&'a ()>,
+}
+
+/// A value that can be passed as an argument to a Java method.
+#[derive(Debug, Clone, Copy)]
+pub enum JValue<'a> {
+	Boolean(bool),
+	Byte(u8),
+	Char(char),
+	Short(i16),
+	Int(i32),
+	Long(i64),
+	Float(f32),
+	Double(f64),
+	Object(JObjectRef<'a>),
+	Null,
+}
+
+impl<'a> From<JValue<'a>> for jvalue {
+	fn from(val: JValue<'a>) -> jvalue {
+		match val {
+			JValue::Boolean(v) => jvalue{ z: <jboolean as JPrimitive>::from(v) },
+			JValue::Byte(v) => jvalue{ b: <jbyte as JPrimitive>::from(v) },
+			JValue::Char(v) => jvalue{ c: <jchar as JPrimitive>::from(v) },
+			JValue::Short(v) => jvalue{ s: <jshort as JPrimitive>::from(v) },
+			JValue::Int(v) => jvalue{ i: <jint as JPrimitive>::from(v) },
+			JValue::Long(v) => jvalue{ j: <jlong as JPrimitive>::from(v) },
+			JValue::Float(v) => jvalue{ f: <jfloat as JPrimitive>::from(v) },
+			JValue::Double(v) => jvalue{ d: <jdouble as JPrimitive>::from(v) },
+			JValue::Object(v) => jvalue{ l: v.ptr },
+			JValue::Null => jvalue{ l: 0 as jobject },
+		}
+	}
+}
+
+impl<'a> JValue<'a> {
+	fn is_compatible(&self, typ: &JavaType) -> bool {
+		match (*self, typ) {
+			(JValue::Boolean(_), &JavaType::Boolean) => true,
+			(JValue::Byte(_), &JavaType::Byte) => true,
+			(JValue::Char(_), &JavaType::Char) => true,
+			(JValue::Short(_), &JavaType::Short) => true,
+			(JValue::Int(_), &JavaType::Int) => true,
+			(JValue::Long(_), &JavaType::Long) => true,
+			(JValue::Float(_), &JavaType::Float) => true,
+			(JValue::Double(_), &JavaType::Double) => true,
+			(JValue::Object(_), t) | (JValue::Null, t) => t.is_reference(),
+			_ => false,
+		}
+	}
+}
+
+impl<'a, 'b, T: 'b + JObject<'b>> From<&'a T> for JValue<'a> {
+	fn from(val: &'a T) -> JValue<'a> {
+		JValue::Object(JObjectRef{
+			ptr: val.get_obj(),
+			phantom: PhantomData,
+		})
+	}
+}
+
+macro_rules! impl_jvalue_from(
+	($rtype:ty, $variant:ident) => (
+		impl<'a> From<$rtype> for JValue<'a> {
+			fn from(val: $rtype) -> JValue<'a> {
+				JValue::$variant(val)
+			}
+		}
+	);
+);
+
+impl_jvalue_from!(bool, Boolean);
+impl_jvalue_from!(u8, Byte);
+impl_jvalue_from!(char, Char);
+impl_jvalue_from!(i16, Short);
+impl_jvalue_from!(i32, Int);
+impl_jvalue_from!(i64, Long);
+impl_jvalue_from!(f32, Float);
+impl_jvalue_from!(f64, Double);
+
 /// Stores an option for the JVM
 #[allow(raw_pointer_derive)]
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -356,15 +464,18 @@ impl JavaVMInitArgs {
 }
 
 /// Stores a group of arguments for attaching to the JVM
+///
+/// The `ThreadGroup` is a global reference, because local references
+/// of other threads can't be used by the thread being attached.
 #[derive(Debug)]
 pub struct JavaVMAttachArgs<'a> {
 	pub version: JniVersion,
 	pub name: String,
-	pub group: JavaObject<'a>,
+	pub group: Option<&'a GlobalRef<JavaObject<'static>>>,
 }
 
 impl<'a> JavaVMAttachArgs<'a> {
-	pub fn new(version: JniVersion, name: &str, group: JavaObject<'a>) -> JavaVMAttachArgs<'a> {
+	pub fn new(version: JniVersion, name: &str, group: Option<&'a GlobalRef<JavaObject<'static>>>) -> JavaVMAttachArgs<'a> {
 		JavaVMAttachArgs{
 			version: version,
 			name: name.to_string(),
@@ -377,18 +488,63 @@ impl<'a> JavaVMAttachArgs<'a> {
 /// It is *not* permissible to use an `Env`
 /// to be used after the `JavaVM` instance corresponding to it
 /// has been destroyed. This is checked by the compiler.
+///
+/// To share a JVM between threads, use `JavaVM::shared`:
+/// the last clone destroys it.
 #[allow(raw_pointer_derive)]
 #[derive(Debug)]
 pub struct JavaVM {
 	ptr: *mut JavaVMImpl,
 	version: JniVersion,
 	owned: bool,
+	/// Set for the copies kept by references, see `unowned`.
+	is_copy: bool,
+	drop_policy: DropPolicy,
+}
+
+/// What dropping a `JavaVM` created by `JavaVM::new` does.
+///
+/// `DestroyJavaVM` waits for all non-daemon Java threads to finish, which
+/// may be forever.  To stop the JVM regardless, call `Runtime.halt`
+/// (or `System.exit`, which also runs the shutdown hooks) before:
+///
+/// ```ignore
+/// let (runtime, cap) = try!(JavaClass::find(&env, "java/lang/Runtime", cap));
+/// let (get, cap) = try!(JavaMethod::new_static(&runtime, "getRuntime", "()Ljava/lang/Runtime;", cap));
+/// let (halt, cap) = try!(JavaMethod::new(&runtime, "halt", "(I)V", cap));
+/// let (rt, cap) = try!(runtime.call_static::<Option<JavaObject>>(&get, &[], cap));
+/// rt.unwrap().call::<()>(&halt, &[JValue::from(1)], cap); // never returns
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DropPolicy {
+	/// Never destroy the JVM.
+	Leak,
+	/// Destroy the JVM, waiting as long as it takes.
+	/// Errors are ignored, use `JavaVM::destroy` to get them.
+	Destroy,
+	/// Destroy the JVM in another thread, and leak it if that does not
+	/// finish in time.  Errors are ignored, like with `Destroy`.
+	DestroyTimeout(Duration),
+}
+
+/// Why `JavaVM::new` failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CreateError {
+	/// `JNI_CreateJavaVM` failed.
+	Jni(JniError),
+	/// `libjvm` could not be loaded, with the `dynamic-jvm` feature.
+	#[cfg(all(unix, feature = "dynamic-jvm"))]
+	Load(LoadError),
 }
 
 impl JavaVM {
 	/// Creates a Java Virtual Machine.
-	/// The JVM will automatically be destroyed when the object goes out of scope.
-	pub fn new(args: JavaVMInitArgs) -> Result<JavaVM, JniError> {
+	/// The JVM will automatically be destroyed when the object goes out of scope,
+	/// unless its `DropPolicy` says otherwise.
+	pub fn new(args: JavaVMInitArgs) -> Result<JavaVM, CreateError> {
+		#[cfg(all(unix, feature = "dynamic-jvm"))]
+		try!(JvmLibrary::find().map_err(CreateError::Load));
+
 		let (res, jvm) = unsafe {
 			let mut jvm: *mut JavaVMImpl = 0 as *mut JavaVMImpl;
 			let mut env: *mut JNIEnvImpl = 0 as *mut JNIEnvImpl;
@@ -423,10 +579,12 @@ impl JavaVM {
 					ptr: jvm,
 					version: args.version,
 					owned: true,
+					is_copy: false,
+					drop_policy: DropPolicy::Destroy,
 				};
 				Ok(r)
 			}
-			_ => Err(res)
+			_ => Err(CreateError::Jni(res))
 		}
 	}
 
@@ -435,13 +593,12 @@ impl JavaVM {
 			ptr: ptr,
 			version: JniVersion::JNI_VERSION_1_1,
 			owned: false,
+			is_copy: false,
+			drop_policy: DropPolicy::Destroy,
 		};
 
-		let version = match res.get_env() {
-			Err(_) => JniVersion::JNI_VERSION_1_1, // well, what do you do...
-			Ok((env, cap)) => env.version(&cap),
-		};
-
+		let mut version = JniVersion::JNI_VERSION_1_1; // well, what do you do...
+		let _ = unsafe { res.with_raw_env(|env| version = env_version(env)) };
 		res.version = version;
 		res
 	}
@@ -471,6 +628,69 @@ impl JavaVM {
 		Ok(res)
 	}
 
+	/// The JVM running in this process, if any, so that it does not have
+	/// to be passed around.  Like the ones from `created`, it is not
+	/// destroyed when dropped.
+	pub fn current() -> Option<JavaVM> {
+		match JavaVM::created() {
+			Ok(jvms) => jvms.into_iter().next(),
+			Err(_) => None,
+		}
+	}
+
+	/// A handle which can be cloned and sent to other threads.
+	pub fn shared(self) -> SharedJavaVM {
+		SharedJavaVM{
+			jvm: Arc::new(self),
+		}
+	}
+
+	/// Runs a `JNI_OnLoad` handler for a JVM that has loaded this library.
+	/// Returns the JNI version requested by `init`, or `JNI_ERR`
+	/// if `init` left an exception pending, which is then thrown
+	/// by `System.loadLibrary`.
+	/// This is what `jni_onload!` expands to.
+	pub unsafe fn on_load<F>(ptr: *mut JavaVMImpl, init: F) -> jint where F: FnOnce(&JavaEnv, Capability) -> JniVersion {
+		let env = match JavaVM::raw_env(ptr) {
+			Some(env) => env,
+			None => return JniError::JNI_ERR as jint,
+		};
+		let version = match env.exception_check() {
+			Ok(cap) => init(&env, cap),
+			Err(_) => return JniError::JNI_ERR as jint,
+		};
+		match env.exception_check() {
+			Ok(_) => version as jint,
+			Err(_) => JniError::JNI_ERR as jint,
+		}
+	}
+
+	/// Runs a `JNI_OnUnload` handler for a JVM that is unloading this library.
+	/// An exception left pending by `fini` is cleared, as there is no one to throw it to.
+	/// This is what `jni_onunload!` expands to.
+	pub unsafe fn on_unload<F>(ptr: *mut JavaVMImpl, fini: F) where F: FnOnce(&JavaEnv, Capability) {
+		let env = match JavaVM::raw_env(ptr) {
+			Some(env) => env,
+			None => return,
+		};
+		if let Ok(cap) = env.exception_check() {
+			fini(&env, cap);
+		}
+		if let Err(exn) = env.exception_check() {
+			env.exception_clear(exn);
+		}
+	}
+
+	/// The env of the current thread, which the JVM has attached,
+	/// borrowed so that dropping it does not check for exceptions.
+	unsafe fn raw_env<'a>(ptr: *mut JavaVMImpl) -> Option<JavaEnv<'a>> {
+		let mut env: *mut JNIEnvImpl = 0 as *mut JNIEnvImpl;
+		if ((**ptr).GetEnv)(ptr, &mut env, JniVersion::JNI_VERSION_1_2) != JniError::JNI_OK {
+			return None;
+		}
+		JavaEnv::from_raw(env).ok()
+	}
+
 	pub unsafe fn ptr(&self) -> *mut JavaVMImpl {
 		self.ptr
 	}
@@ -482,18 +702,57 @@ impl JavaVM {
 	pub fn get_env(&self) -> Result<(JavaEnv, Capability), JniError> {
 		unsafe {
 			let ref jni = **self.ptr;
-			self.get_env_gen(jni.AttachCurrentThread)
+			self.get_env_gen(jni.AttachCurrentThread, None, false)
 		}
 	}
 
 	pub fn get_env_daemon(&self) -> Result<(JavaEnv, Capability), JniError> {
 		unsafe {
 			let ref jni = **self.ptr;
-			self.get_env_gen(jni.AttachCurrentThreadAsDaemon)
+			self.get_env_gen(jni.AttachCurrentThreadAsDaemon, None, false)
+		}
+	}
+
+	/// Like `get_env`, but the thread gets the name and the `ThreadGroup`
+	/// from `args` if it is attached.  They show up in `jstack` and profilers.
+	pub fn attach(&self, args: JavaVMAttachArgs) -> Result<(JavaEnv, Capability), JniError> {
+		unsafe {
+			let ref jni = **self.ptr;
+			self.get_env_gen(jni.AttachCurrentThread, Some(&args), false)
 		}
 	}
 
-	unsafe fn get_env_gen(&self, fun: extern "C" fn(vm: *mut JavaVMImpl, penv: &mut *mut JNIEnvImpl, args: *mut JavaVMAttachArgsImpl) -> JniError) -> Result<(JavaEnv, Capability), JniError> {
+	/// Like `get_env_daemon`, but the thread gets the name and the `ThreadGroup`
+	/// from `args` if it is attached.
+	pub fn attach_daemon(&self, args: JavaVMAttachArgs) -> Result<(JavaEnv, Capability), JniError> {
+		unsafe {
+			let ref jni = **self.ptr;
+			self.get_env_gen(jni.AttachCurrentThreadAsDaemon, Some(&args), false)
+		}
+	}
+
+	/// Like `get_env`, but a thread attached by it stays attached
+	/// until it exits, so later calls on the thread don't attach again.
+	///
+	/// A non-daemon thread attached this way keeps `DestroyJavaVM` waiting,
+	/// so the thread has to exit before the `JavaVM` is dropped.
+	pub fn get_env_cached(&self) -> Result<(JavaEnv, Capability), JniError> {
+		unsafe {
+			let ref jni = **self.ptr;
+			self.get_env_gen(jni.AttachCurrentThread, None, true)
+		}
+	}
+
+	/// Like `get_env_daemon`, but a thread attached by it stays attached
+	/// until it exits.
+	pub fn get_env_daemon_cached(&self) -> Result<(JavaEnv, Capability), JniError> {
+		unsafe {
+			let ref jni = **self.ptr;
+			self.get_env_gen(jni.AttachCurrentThreadAsDaemon, None, true)
+		}
+	}
+
+	unsafe fn get_env_gen(&self, fun: extern "C" fn(vm: *mut JavaVMImpl, penv: &mut *mut JNIEnvImpl, args: *mut JavaVMAttachArgsImpl) -> JniError, args: Option<&JavaVMAttachArgs>, cache: bool) -> Result<(JavaEnv, Capability), JniError> {
 		let mut env: *mut JNIEnvImpl = 0 as *mut JNIEnvImpl;
 		let res = ((**self.ptr).GetEnv)(self.ptr, &mut env, self.version());
 		match res {
@@ -501,6 +760,9 @@ impl JavaVM {
 				ptr: &mut *env,
 				jvm: self,
 				detach: false,
+				borrowed: false,
+				refs: LocalRefs::new(),
+				frames: Cell::new(0),
 			}, Capability::new())),
 			JniError::JNI_EDETACHED => {
 				let mut attachArgs = JavaVMAttachArgsImpl{
@@ -508,12 +770,27 @@ impl JavaVM {
 					name: 0 as *const ::libc::c_char,
 					group: 0 as jobject
 				};
+				// has to live until the thread is attached
+				let name = args.map(|args| JavaChars::new(&args.name));
+				if let Some(args) = args {
+					attachArgs.version = args.version;
+					attachArgs.name = name.as_ref().unwrap().as_ptr();
+					if let Some(group) = args.group {
+						attachArgs.group = group.ptr;
+					}
+				}
 				let res = fun(self.ptr, &mut env, &mut attachArgs);
+				if res == JniError::JNI_OK && cache {
+					ATTACHMENT.with(|a| *a.borrow_mut() = Some(ThreadAttachment{ jvm: self.ptr }));
+				}
 				match res {
 					JniError::JNI_OK => Ok((JavaEnv{
 						ptr: &mut *env,
 						jvm: self,
-						detach: true,
+						detach: !cache,
+						borrowed: false,
+						refs: LocalRefs::new(),
+						frames: Cell::new(0),
 					}, Capability::new())),
 					_ => Err(res)
 				}
@@ -522,19 +799,149 @@ impl JavaVM {
 		}
 	}
 
+	/// Runs `f` with the raw `JNIEnv` of the current thread, attaching
+	/// it for the call if needed.  Unlike `get_env`, nothing is checked
+	/// when it is done, so it can be used in `Drop` with an exception pending.
+	unsafe fn with_raw_env<F>(&self, f: F) -> Result<(), JniError> where F: FnOnce(*mut JNIEnvImpl) {
+		if JVM_DESTROYED.load(Ordering::SeqCst) {
+			return Err(JniError::JNI_ERR);
+		}
+		let mut env: *mut JNIEnvImpl = 0 as *mut JNIEnvImpl;
+		match ((**self.ptr).GetEnv)(self.ptr, &mut env, self.version()) {
+			JniError::JNI_OK => {
+				f(env);
+				Ok(())
+			},
+			JniError::JNI_EDETACHED => {
+				let res = ((**self.ptr).AttachCurrentThread)(self.ptr, &mut env, 0 as *mut JavaVMAttachArgsImpl);
+				if res != JniError::JNI_OK {
+					return Err(res);
+				}
+				f(env);
+				match ((**self.ptr).DetachCurrentThread)(self.ptr) {
+					JniError::JNI_OK => Ok(()),
+					err => Err(err),
+				}
+			},
+			err => Err(err),
+		}
+	}
+
+	/// A copy of this `JavaVM` which does not destroy it,
+	/// nor report leaked references when dropped.
+	fn unowned(&self) -> JavaVM {
+		JavaVM{
+			ptr: self.ptr,
+			version: self.version,
+			owned: false,
+			is_copy: true,
+			drop_policy: DropPolicy::Destroy,
+		}
+	}
+
+	/// Sets what dropping this `JavaVM` does, `DropPolicy::Destroy` by default.
+	pub fn set_drop_policy(&mut self, policy: DropPolicy) {
+		self.drop_policy = policy;
+	}
+
+	/// Destroys the JVM, waiting for all non-daemon Java threads to finish.
+	/// Unlike dropping the `JavaVM`, this reports errors.  Only a `JavaVM`
+	/// created by `JavaVM::new` can be destroyed, for others this is
+	/// `JNI_EINVAL`.
+	pub fn destroy(mut self) -> Result<(), JniError> {
+		if !self.owned {
+			return Err(JniError::JNI_EINVAL);
+		}
+		let err = unsafe { self.destroy_java_vm() };
+		if err == JniError::JNI_OK {
+			Ok(())
+		} else {
+			// the error is reported, dropping must not try again
+			self.drop_policy = DropPolicy::Leak;
+			Err(err)
+		}
+	}
+
 	unsafe fn destroy_java_vm(&mut self) -> JniError {
 		if self.ptr == 0 as *mut JavaVMImpl {
 			return JniError::JNI_OK;
 		}
 
+		ref_tracker::report_globals();
 		let err = ((**self.ptr).DestroyJavaVM)(self.ptr);
-		self.ptr = 0 as *mut JavaVMImpl;
+		// if it failed, the JVM is still running and can be used
+		if err == JniError::JNI_OK {
+			JVM_DESTROYED.store(true, Ordering::SeqCst);
+			self.ptr = 0 as *mut JavaVMImpl;
+		}
 		err
 	}
+
+	/// Detaches the current thread if it is attached, so that another
+	/// thread can destroy the JVM: `DestroyJavaVM` waits for all the other
+	/// non-daemon threads, including the one which created the JVM.
+	unsafe fn detach_current_thread(&self) {
+		// dropping the attachment of `get_env_cached` detaches the thread
+		let _ = ATTACHMENT.try_with(|a| a.borrow_mut().take());
+		let mut env: *mut JNIEnvImpl = 0 as *mut JNIEnvImpl;
+		if ((**self.ptr).GetEnv)(self.ptr, &mut env, self.version()) == JniError::JNI_OK {
+			let _ = ((**self.ptr).DetachCurrentThread)(self.ptr);
+		}
+	}
 }
 
+// the JVM can be used and destroyed from any thread
+unsafe impl Send for JavaVM {}
 unsafe impl Sync for JavaVM {}
 
+/// A `JavaVM` shared between threads.  The last clone to be dropped
+/// drops the `JavaVM`, which destroys it according to its `DropPolicy`.
+#[derive(Debug, Clone)]
+pub struct SharedJavaVM {
+	jvm: Arc<JavaVM>,
+}
+
+impl SharedJavaVM {
+	/// Gets the `JavaVM` back if this is the last clone,
+	/// for example to `destroy` it.
+	pub fn try_unwrap(self) -> Result<JavaVM, SharedJavaVM> {
+		match Arc::try_unwrap(self.jvm) {
+			Ok(jvm) => Ok(jvm),
+			Err(jvm) => Err(SharedJavaVM{ jvm: jvm }),
+		}
+	}
+}
+
+impl Deref for SharedJavaVM {
+	type Target = JavaVM;
+
+	fn deref(&self) -> &JavaVM {
+		&self.jvm
+	}
+}
+
+/// Set when the JVM is destroyed, after which it must not be used even by
+/// threads that are still attached.  There is only one JVM in a process,
+/// and it can't be created again.
+static JVM_DESTROYED: AtomicBool = AtomicBool::new(false);
+
+/// Detaches the thread attached by `get_env_cached` when it exits.
+struct ThreadAttachment {
+	jvm: *mut JavaVMImpl,
+}
+
+impl Drop for ThreadAttachment {
+	fn drop(&mut self) {
+		// `DestroyJavaVM` already detached the thread, and an error can't
+		// be reported from a thread-local destructor
+		if !JVM_DESTROYED.load(Ordering::SeqCst) {
+			let _ = unsafe { ((**self.jvm).DetachCurrentThread)(self.jvm) };
+		}
+	}
+}
+
+thread_local!(static ATTACHMENT: RefCell<Option<ThreadAttachment>> = RefCell::new(None));
+
 impl PartialEq for JavaVM {
 	fn eq(&self, r: &Self) -> bool {
 		self.ptr == r.ptr
@@ -545,43 +952,171 @@ impl Eq for JavaVM {}
 
 impl Drop for JavaVM {
 	fn drop(&mut self) {
-		if !self.owned {
+		if self.ptr == 0 as *mut JavaVMImpl || self.is_copy {
+			return;
+		}
+		// the JVM keeps running, so the references still alive
+		// are reported here instead of when it is destroyed
+		if !self.owned || self.drop_policy == DropPolicy::Leak {
+			ref_tracker::report_globals();
 			return;
 		}
 
-		let err = unsafe { self.destroy_java_vm() };
-		if err != JniError::JNI_OK {
-			panic!("DestroyJavaVM error: {:?}", err);
+		// panicking here would abort the process if already unwinding,
+		// so errors are ignored, `destroy` reports them
+		match self.drop_policy {
+			DropPolicy::Leak => (),
+			DropPolicy::Destroy => {
+				let _ = unsafe { self.destroy_java_vm() };
+			},
+			DropPolicy::DestroyTimeout(timeout) => {
+				unsafe { self.detach_current_thread() };
+				let jvm = JavaVM{
+					ptr: self.ptr,
+					version: self.version,
+					owned: true,
+					is_copy: false,
+					drop_policy: DropPolicy::Destroy,
+				};
+				self.ptr = 0 as *mut JavaVMImpl;
+				let (tx, rx) = mpsc::channel();
+				thread::spawn(move || {
+					let _ = tx.send(jvm.destroy());
+				});
+				let _ = rx.recv_timeout(timeout);
+			},
 		}
 	}
 }
 
+/// Exports `JNI_OnLoad` from a library loaded by `System.loadLibrary`.
+///
+/// Takes a closure which gets a `JavaEnv` and a `Capability`
+/// and returns the JNI version the library needs:
+///
+/// ```ignore
+/// jni_onload!(|env, cap| {
+///     // ...
+///     JniVersion::JNI_VERSION_1_6
+/// });
+/// ```
+#[macro_export]
+macro_rules! jni_onload(
+	($init:expr) => (
+		#[no_mangle]
+		#[allow(non_snake_case)]
+		pub unsafe extern "system" fn JNI_OnLoad(vm: *mut $crate::native::JavaVMImpl, _reserved: *mut $crate::native::jvoid) -> $crate::native::jint {
+			$crate::JavaVM::on_load(vm, $init)
+		}
+	);
+);
+
+/// Exports `JNI_OnUnload` from a library loaded by `System.loadLibrary`.
+///
+/// Takes a closure which gets a `JavaEnv` and a `Capability`.
+#[macro_export]
+macro_rules! jni_onunload(
+	($fini:expr) => (
+		#[no_mangle]
+		#[allow(non_snake_case)]
+		pub unsafe extern "system" fn JNI_OnUnload(vm: *mut $crate::native::JavaVMImpl, _reserved: *mut $crate::native::jvoid) {
+			$crate::JavaVM::on_unload(vm, $fini)
+		}
+	);
+);
+
 /// Represents an environment pointer used by the JNI.
 /// Serves as an upper bound to the lifetime of all local refs
 /// created by this binding.
 ///
-/// TODO: allow for global/weak refs to outlive their env.
+/// Use `GlobalRef` and `WeakRef` for refs that outlive their env.
 #[derive(Debug)]
 #[allow(raw_pointer_derive)]
 pub struct JavaEnv<'a> {
 	ptr: *mut JNIEnvImpl,
 	jvm: &'a JavaVM,
 	detach: bool,
+	borrowed: bool,
+	refs: LocalRefs,
+	/// The number of frames of `with_local_frame` open on this env.
+	frames: Cell<usize>,
+}
+
+/// The `JavaEnv` of a frame of `JavaEnv::with_local_frame`.
+///
+/// `'a` is the lifetime of the outer env, so that its refs can be used
+/// in the frame.
+#[derive(Debug)]
+pub struct LocalFrame<'b, 'a: 'b> {
+	env: JavaEnv<'b>,
+	outer: PhantomData<&'b JavaEnv<'a>>,
+}
+
+impl<'b, 'a> Deref for LocalFrame<'b, 'a> {
+	type Target = JavaEnv<'b>;
+
+	fn deref(&self) -> &JavaEnv<'b> {
+		&self.env
+	}
+}
+
+// There can only be one JVM in a process, so the `JavaVM` for
+// environments passed to native methods is created once and never freed.
+static RAW_JVM: OnceLock<JavaVM> = OnceLock::new();
+
+fn raw_jvm(jvm: *mut JavaVMImpl, env: *mut JNIEnvImpl) -> &'static JavaVM {
+	let res = RAW_JVM.get_or_init(|| JavaVM{
+		ptr: jvm,
+		version: unsafe { env_version(env) },
+		owned: false,
+		is_copy: false,
+		drop_policy: DropPolicy::Destroy,
+	});
+	assert!(res.ptr == jvm);
+	res
+}
+
+/// The version of a `JNIEnv`, which can be asked for with an exception pending.
+unsafe fn env_version(env: *mut JNIEnvImpl) -> JniVersion {
+	let ver = ((**env).GetVersion)(env) as u32;
+	// the JVM supports at least 1.1
+	JniVersion::from_raw(ver).unwrap_or(JniVersion::JNI_VERSION_1_1)
 }
 
 impl<'a> JavaEnv<'a> {
+	/// Wraps the `JNIEnv` pointer passed to a native method.
+	/// The thread is never detached, and an exception still pending when
+	/// the `JavaEnv` is dropped is thrown to the Java caller.
+	/// Returns the error of `GetJavaVM` if it fails.
+	pub unsafe fn from_raw(ptr: *mut JNIEnvImpl) -> Result<JavaEnv<'a>, JniError> {
+		let mut jvm: *mut JavaVMImpl = 0 as *mut JavaVMImpl;
+		let err = ((**ptr).GetJavaVM)(ptr, &mut jvm);
+		if err != JniError::JNI_OK {
+			return Err(err);
+		}
+		Ok(JavaEnv{
+			ptr: ptr,
+			jvm: raw_jvm(jvm, ptr),
+			detach: false,
+			borrowed: true,
+			refs: LocalRefs::new(),
+			frames: Cell::new(0),
+		})
+	}
+
+	pub unsafe fn ptr(&self) -> *mut JNIEnvImpl {
+		self.ptr
+	}
+
 	/// Get the underlying JavaVM reference.
 	pub fn jvm(&self) -> &'a JavaVM {
 		self.jvm
 	}
 
 	/// Gets the version of the JVM (mightt be bigger, than the JavaVM args version, but not less)
+	/// Versions newer than the known ones are reported as the newest known one.
 	pub fn version(&self, _cap: &Capability) -> JniVersion {
-		let ver = unsafe { ((**self.ptr).GetVersion)(self.ptr) } as u32;
-		match ver {
-			MIN_JNI_VERSION ... MAX_JNI_VERSION => unsafe { mem::transmute(ver) },
-			_ => panic!("Unsupported version {:?}!", ver),
-		}
+		unsafe { env_version(self.ptr) }
 	}
 
 	/// Defines a Java class from a name, ClassLoader, buffer, and length
@@ -639,6 +1174,75 @@ impl<'a> JavaEnv<'a> {
 		}
 	}
 
+	/// Loads the class of a reference type with the loader of `cls`, the
+	/// way the JVM resolves the types in the descriptors of its members.
+	/// Returns `None` for primitive types and `java.lang.Object`, which
+	/// need no checks.
+	fn resolve_class(&'a self, cls: &JavaClass, typ: &JavaType, cap: Capability) -> JniResult<Option<JavaClass<'a>>> {
+		let name = match typ.class_name() {
+			Some(ref name) if name != "java/lang/Object" => name.replace('/', "."),
+			_ => return Ok((None, cap)),
+		};
+		// the calls are made directly, since checked calls resolve classes themselves
+		let (class, cap) = try!(self.find_class("java/lang/Class", cap));
+		let (get_loader, cap) = try!(self.get_method_id(&class, "getClassLoader", "()Ljava/lang/ClassLoader;", cap));
+		let (for_name, cap) = try!(self.get_static_method_id(&class, "forName", "(Ljava/lang/String;ZLjava/lang/ClassLoader;)Ljava/lang/Class;", cap));
+		let (name, cap) = try!(self.new_string(&name, cap));
+		let (loader, _) = unsafe {
+			(((**self.ptr).CallObjectMethodA)(self.ptr, cls.ptr, get_loader, ::std::ptr::null()), cap)
+		};
+		// here `cap` is taken, we can't call any Jni methods
+		let loader: Option<JavaObject> = JObject::from(self, loader);
+		let cap = try!(self.exception_check());
+		let args = [
+			jvalue{ l: name.ptr },
+			jvalue{ z: JNI_FALSE },
+			jvalue{ l: loader.as_ref().map_or(0 as jobject, |l| l.ptr) },
+		];
+		let (res, _) = unsafe {
+			(((**self.ptr).CallStaticObjectMethodA)(self.ptr, class.ptr, for_name, args.as_ptr()), cap)
+		};
+		// here `cap` is taken, we can't call any Jni methods
+		let res = JObject::from(self, res);
+		let cap = try!(self.exception_check());
+		Ok((res, cap))
+	}
+
+	/// Checks if a value of the declared type `decl`, of the class `decl_cls`
+	/// resolved by `resolve_class`, can be used as the Rust type `typ`.
+	fn is_castable(&self, decl: &JavaType, decl_cls: Option<&JavaClass>, typ: &JavaType, cap: Capability) -> JniResult<bool> {
+		if !typ.is_compatible(decl) {
+			return Ok((false, cap));
+		}
+		let name = match typ.class_name() {
+			Some(name) => if typ == decl || name == "java/lang/Object" {
+				return Ok((true, cap));
+			} else {
+				name
+			},
+			None => return Ok((true, cap)),
+		};
+		let decl_cls = match decl_cls {
+			Some(decl_cls) => decl_cls,
+			// only `java.lang.Object` isn't resolved
+			None => return Ok((false, cap)),
+		};
+		let (cls, cap) = try!(self.find_class(&name, cap));
+		let res = self.is_assignable_from(decl_cls, &cls, &cap);
+		Ok((res, cap))
+	}
+
+	/// Checks if a value is `null`, primitive, or an instance of `cls`.
+	/// A `cls` of `None` stands for `java.lang.Object`, like in `resolve_class`.
+	fn is_value_of(&self, val: &JValue, cls: Option<&JavaClass>, _cap: &Capability) -> bool {
+		match (*val, cls) {
+			(JValue::Object(obj), Some(cls)) => unsafe {
+				((**self.ptr).IsInstanceOf)(self.ptr, obj.ptr, cls.ptr) == JNI_TRUE
+			},
+			_ => true,
+		}
+	}
+
 	/// Throw a Java exception. The actual exception will be thrown
 	/// when the function returns.
 	fn throw(&self, obj: &JavaThrowable, cap: Capability) -> Result<Exception, JniError> {
@@ -666,6 +1270,27 @@ impl<'a> JavaEnv<'a> {
 		}
 	}
 
+	fn throw_new_by_name(&self, name: &str, msg: &str, cap: Capability) -> Exception {
+		let (cls, cap) = match self.find_class(name, cap) {
+			Ok(v) => v,
+			Err(ex) => return ex,
+		};
+		match self.throw_new(&cls, msg, cap) {
+			Ok(ex) => ex,
+			Err(err) => self.throw_failed(err),
+		}
+	}
+
+	/// The `Exception` for a failed `Throw` or `ThrowNew`, which leaves the
+	/// error that made it fail pending, usually an `OutOfMemoryError`.
+	/// Without one, no `Exception` can be returned, so the JVM is stopped.
+	fn throw_failed(&self, err: JniError) -> Exception {
+		match self.exception_check() {
+			Err(exn) => exn,
+			Ok(_) => self.fatal_error(&format!("Throw error: {:?}", err)),
+		}
+	}
+
 	fn exception_check(&self) -> Result<Capability, Exception> {
 		let ex = unsafe {
 			((**self.ptr).ExceptionCheck)(self.ptr) == JNI_TRUE
@@ -677,6 +1302,209 @@ impl<'a> JavaEnv<'a> {
 		}
 	}
 
+	fn throw_error<E: ToJavaException + ?Sized>(&self, err: &E, cap: Capability) -> Exception {
+		let cause = match err.exception_cause() {
+			None => return self.throw_new_by_name(err.exception_class(), &err.exception_message(), cap),
+			Some(cause) => cause,
+		};
+		let (ex, cap) = match self.new_throwable(err.exception_class(), &err.exception_message(), Some(cause), cap) {
+			Ok(v) => v,
+			Err(ex) => return ex,
+		};
+		match self.throw(&ex, cap) {
+			Ok(ex) => ex,
+			Err(err) => self.throw_failed(err),
+		}
+	}
+
+	/// Creates a throwable with a message and the chain of `cause` as its causes.
+	/// Causes are thrown as `RuntimeException`s.
+	fn new_throwable(&self, name: &str, msg: &str, cause: Option<&dyn Error>, cap: Capability) -> JniResult<JavaThrowable> {
+		let (cause, cap) = match cause {
+			None => (None, cap),
+			Some(err) => {
+				let (cause, cap) = try!(self.new_throwable("java/lang/RuntimeException", &err.to_string(), err.source(), cap));
+				(Some(cause), cap)
+			}
+		};
+		let (cls, cap) = try!(self.find_class(name, cap));
+		let (msg, cap) = try!(JavaString::new(self, msg, cap));
+		let (obj, cap) = try!(cls.new_object("(Ljava/lang/String;)V", &[JValue::from(&msg)], cap));
+		let ex: JavaThrowable = unsafe { JObject::from_unsafe(self, obj.into_raw()) };
+		let cap = match cause {
+			None => cap,
+			Some(cause) => {
+				let (init_cause, cap) = try!(JavaMethod::new(&cls, "initCause", "(Ljava/lang/Throwable;)Ljava/lang/Throwable;", cap));
+				let (_, cap) = try!(ex.call::<Option<JavaThrowable>>(&init_cause, &[JValue::from(&cause)], cap));
+				cap
+			}
+		};
+		Ok((ex, cap))
+	}
+
+	/// Clears the pending exception if it is an instance of `cls`,
+	/// otherwise it is thrown again.
+	fn catch_exception(&self, cls: &JavaClass, exn: Exception) -> Result<(JavaThrowable, Capability), Exception> {
+		let ex = match self.exception_occured() {
+			Ok(_) => panic!("No pending exception."),
+			Err((ex, _)) => ex,
+		};
+		let cap = self.exception_clear(exn);
+		if ex.is_instance_of(cls, &cap) {
+			Ok((ex, cap))
+		} else {
+			self.rethrow(&ex, cap)
+		}
+	}
+
+	fn catch_exception_by_name(&self, name: &str, exn: Exception) -> Result<(JavaThrowable, Capability), Exception> {
+		let ex = match self.exception_occured() {
+			Ok(_) => panic!("No pending exception."),
+			Err((ex, _)) => ex,
+		};
+		let cap = self.exception_clear(exn);
+		let (cls, cap) = match self.find_class(name, cap) {
+			Ok(v) => v,
+			// the pending exception can't be of a class that can't be loaded
+			Err(exn) => {
+				let cap = self.exception_clear(exn);
+				return self.rethrow(&ex, cap);
+			}
+		};
+		if ex.is_instance_of(&cls, &cap) {
+			Ok((ex, cap))
+		} else {
+			self.rethrow(&ex, cap)
+		}
+	}
+
+	fn rethrow<T>(&self, ex: &JavaThrowable, cap: Capability) -> Result<T, Exception> {
+		match self.throw(ex, cap) {
+			Ok(exn) => Err(exn),
+			Err(err) => Err(self.throw_failed(err)),
+		}
+	}
+
+	/// Reads and clears the pending exception.  If it can't be read,
+	/// it is thrown again and returned as `Err`.
+	fn take_exception(&self, exn: Exception) -> JniResult<JavaException> {
+		let ex = match self.exception_occured() {
+			Ok(_) => panic!("No pending exception."),
+			Err((ex, _)) => ex,
+		};
+		let cap = self.exception_clear(exn);
+		match self.read_exception(&ex, cap) {
+			Ok(res) => Ok(res),
+			Err(exn) => {
+				let cap = self.exception_clear(exn);
+				self.rethrow(&ex, cap)
+			}
+		}
+	}
+
+	/// Reads a throwable and its causes.
+	fn read_exception(&self, ex: &JavaThrowable, cap: Capability) -> JniResult<JavaException> {
+		let (class, cap) = try!(self.find_class("java/lang/Class", cap));
+		let (throwable, cap) = try!(self.find_class("java/lang/Throwable", cap));
+		let (element, cap) = try!(self.find_class("java/lang/StackTraceElement", cap));
+		let (get_name, cap) = try!(JavaMethod::new(&class, "getName", "()Ljava/lang/String;", cap));
+		let (get_message, cap) = try!(JavaMethod::new(&throwable, "getMessage", "()Ljava/lang/String;", cap));
+		let (get_stack_trace, cap) = try!(JavaMethod::new(&throwable, "getStackTrace", "()[Ljava/lang/StackTraceElement;", cap));
+		let (get_cause, cap) = try!(JavaMethod::new(&throwable, "getCause", "()Ljava/lang/Throwable;", cap));
+		let (get_class_name, cap) = try!(JavaMethod::new(&element, "getClassName", "()Ljava/lang/String;", cap));
+		let (get_method_name, cap) = try!(JavaMethod::new(&element, "getMethodName", "()Ljava/lang/String;", cap));
+		let (get_file_name, cap) = try!(JavaMethod::new(&element, "getFileName", "()Ljava/lang/String;", cap));
+		let (get_line_number, cap) = try!(JavaMethod::new(&element, "getLineNumber", "()I", cap));
+		let methods = ExceptionMethods{
+			get_name: get_name,
+			get_message: get_message,
+			get_stack_trace: get_stack_trace,
+			get_cause: get_cause,
+			get_class_name: get_class_name,
+			get_method_name: get_method_name,
+			get_file_name: get_file_name,
+			get_line_number: get_line_number,
+		};
+		self.read_throwable(ex, &[], &methods, cap)
+	}
+
+	/// Reads a throwable and its causes, `seen` are the throwables it is a cause of.
+	fn read_throwable(&self, ex: &JavaThrowable, seen: &[&JavaThrowable], methods: &ExceptionMethods, cap: Capability) -> JniResult<JavaException> {
+		let (class_name, cap) = try!(ex.get_class(&cap).call::<Option<JavaString>>(&methods.get_name, &[], cap));
+		let class_name = class_name.and_then(|s| s.to_str(&cap)).unwrap_or_default();
+		let (message, cap) = try!(ex.call::<Option<JavaString>>(&methods.get_message, &[], cap));
+		let message = message.and_then(|msg| msg.to_str(&cap));
+
+		let (frames, mut cap) = try!(ex.call::<Option<JavaArray<JavaObject>>>(&methods.get_stack_trace, &[], cap));
+		let mut stack_trace = vec![];
+		if let Some(frames) = frames {
+			for i in 0..frames.len(&cap) {
+				let (frame, c) = try!(frames.get(i, cap));
+				let (frame, c) = try!(self.read_stack_frame(&frame, methods, c));
+				stack_trace.push(frame);
+				cap = c;
+			}
+		}
+
+		let (cause, cap) = try!(ex.call::<Option<JavaThrowable>>(&methods.get_cause, &[], cap));
+		let (cause, cap) = match cause {
+			Some(ref cause) if cause != ex && !seen.iter().any(|s| cause == *s) => {
+				let mut seen = seen.to_vec();
+				seen.push(ex);
+				let (cause, cap) = try!(self.read_throwable(cause, &seen, methods, cap));
+				(Some(Box::new(cause)), cap)
+			}
+			_ => (None, cap),
+		};
+
+		let res = JavaException{
+			class_name: class_name,
+			message: message,
+			stack_trace: stack_trace,
+			cause: cause,
+		};
+		Ok((res, cap))
+	}
+
+	fn read_stack_frame(&self, frame: &JavaObject, methods: &ExceptionMethods, cap: Capability) -> JniResult<JavaStackFrame> {
+		let (class_name, cap) = try!(frame.call::<Option<JavaString>>(&methods.get_class_name, &[], cap));
+		let (method_name, cap) = try!(frame.call::<Option<JavaString>>(&methods.get_method_name, &[], cap));
+		let (file_name, cap) = try!(frame.call::<Option<JavaString>>(&methods.get_file_name, &[], cap));
+		let (line_number, cap) = try!(frame.call::<i32>(&methods.get_line_number, &[], cap));
+		let res = JavaStackFrame{
+			class_name: class_name.and_then(|s| s.to_str(&cap)).unwrap_or_default(),
+			method_name: method_name.and_then(|s| s.to_str(&cap)).unwrap_or_default(),
+			file_name: file_name.and_then(|s| s.to_str(&cap)),
+			line_number: line_number,
+		};
+		Ok((res, cap))
+	}
+
+	fn catch_panic<R: JNativeResult, F: FnOnce() -> R>(&self, cls: &str, f: F) -> R::Raw {
+		install_panic_hook();
+		CATCHING_PANICS.with(|n| n.set(n.get() + 1));
+		let res = panic::catch_unwind(AssertUnwindSafe(|| f().into_native(self)));
+		CATCHING_PANICS.with(|n| n.set(n.get() - 1));
+		let payload = match res {
+			Ok(res) => return res,
+			Err(payload) => payload,
+		};
+
+		// a pending Java exception is likely what caused the panic, so it is kept
+		if let Ok(cap) = self.exception_check() {
+			let msg = panic_message(&*payload);
+			// if the class can't be found, that exception is thrown instead
+			if let Ok((cls, cap)) = self.find_class(cls, cap) {
+				// a failed `ThrowNew` usually leaves an `OutOfMemoryError`,
+				// but the panic can't be lost, nor unwind into the JVM
+				if self.throw_new(&cls, &msg, cap).is_err() && self.exception_check().is_ok() {
+					self.fatal_error(&msg);
+				}
+			}
+		}
+		R::Raw::default_value()
+	}
+
 	fn exception_occured(&self) -> Result<Capability, (JavaThrowable, Exception)> {
 		let obj = unsafe {
 			((**self.ptr).ExceptionOccurred)(self.ptr) as jobject
@@ -729,61 +1557,181 @@ impl<'a> JavaEnv<'a> {
 		};
 	}
 
-	pub fn pop_local_frame<T: 'a + JObject<'a>>(&'a self, result: &'a T, _cap: &Capability) -> T {
+	pub fn pop_local_frame<T: 'a + JObject<'a>>(&'a self, result: T, _cap: &Capability) -> T {
 		let r = unsafe {
-			((**self.ptr).PopLocalFrame)(self.ptr, result.get_obj())
+			((**self.ptr).PopLocalFrame)(self.ptr, result.into_raw())
 		};
 		// documentation says, it never returns null
 		assert!(r != 0 as jobject);
 		unsafe { JObject::from_unsafe(self, r) }
 	}
 
-	fn is_same_object<'b, 'c, T1: 'b + JObject<'b>, T2: 'c + JObject<'c>>(&self, obj1: &T1, obj2: &T2, _cap: &Capability) -> bool {
-		assert!(obj1.jvm() == obj2.jvm());
-		unsafe {
-			((**self.ptr).IsSameObject)(self.ptr, obj1.get_obj(), obj2.get_obj()) == JNI_TRUE
-		}
-	}
-
+	/// Runs `f` in a new frame of local refs, which is popped afterwards,
+	/// even if `f` fails or panics.  The only local ref that outlives the
+	/// frame is the result of `f`, which is moved to the outer frame.
+	///
+	/// `T` is the type of the result with the `'static` lifetime, like
+	/// `JavaString<'static>`.
+	///
+	/// `f` gets a `LocalFrame`, a `JavaEnv` with a lifetime of its own, so
+	/// refs of the frame can't outlive it, and only the result is rebound
+	/// to the lifetime of `self`.  Refs of `self` can be used in the frame:
+	///
+	/// ```compile_fail
+	/// # use jni::*;
+	/// fn escape(env: &JavaEnv, cap: Capability) {
+	///     let mut s = None;
+	///     let _ = env.with_local_frame::<JavaString<'static>, _>(4, cap, |env, cap| {
+	///         let (st, cap) = try!(JavaString::new(env, "escape", cap));
+	///         s = Some(st);
+	///         Ok((None, cap))
+	///     });
+	/// }
+	/// ```
+	///
+	/// Local refs created through `self` while the frame is open would be
+	/// deleted with it, so creating one panics.
+	pub fn with_local_frame<T, F>(&'a self, capacity: usize, cap: Capability, f: F) -> JniResult<Option<<T as JRebind<'a>>::Local>>
+		where T: for<'b> JRebind<'b>,
+		      F: for<'b> FnOnce(&'b LocalFrame<'b, 'a>, Capability) -> JniResult<Option<<T as JRebind<'b>>::Local>>,
+		      for<'b> <T as JRebind<'b>>::Local: JObject<'b> {
+		let cap = match self.push_local_frame(capacity as isize, cap) {
+			Ok(cap) => cap,
+			Err((_, exn)) => return Err(exn),
+		};
+		self.frames.set(self.frames.get() + 1);
+		// a new env, so that refs from the frame can't outlive it
+		let frame = LocalFrame{
+			env: JavaEnv{
+				ptr: self.ptr,
+				jvm: self.jvm,
+				detach: false,
+				borrowed: true,
+				refs: LocalRefs::new(),
+				frames: Cell::new(0),
+			},
+			outer: PhantomData,
+		};
+		let res = panic::catch_unwind(AssertUnwindSafe(|| {
+			match f(&frame, cap) {
+				Ok((Some(obj), _)) => Ok(obj.into_raw()),
+				Ok((None, _)) => Ok(0 as jobject),
+				Err(exn) => Err(exn),
+			}
+		}));
+		self.frames.set(self.frames.get() - 1);
+		// `PopLocalFrame` is safe to call with a pending exception
+		let popped = unsafe {
+			((**self.ptr).PopLocalFrame)(self.ptr, match res {
+				Ok(Ok(obj)) => obj,
+				_ => 0 as jobject,
+			})
+		};
+		match res {
+			Ok(Ok(_)) => Ok((JObject::from(self, popped), Capability::new())),
+			Ok(Err(exn)) => Err(exn),
+			Err(payload) => panic::resume_unwind(payload),
+		}
+	}
+
+	fn is_same_object<'b, 'c, T1: 'b + JObject<'b>, T2: 'c + JObject<'c>>(&self, obj1: &T1, obj2: &T2, _cap: &Capability) -> bool {
+		assert!(obj1.jvm() == obj2.jvm());
+		unsafe {
+			((**self.ptr).IsSameObject)(self.ptr, obj1.get_obj(), obj2.get_obj()) == JNI_TRUE
+		}
+	}
+
 	fn is_null<'b, T: 'b + JObject<'b>>(&self, obj: &T, _cap: &Capability) -> bool {
 		unsafe {
 			((**self.ptr).IsSameObject)(self.ptr, obj.get_obj(), 0 as jobject) == JNI_TRUE
 		}
 	}
 
+	/// Gets the kind of a reference, or `None` if it is invalid.
+	fn get_object_ref_type<T: 'a + JObject<'a>>(&self, obj: &T, _cap: &Capability) -> Option<RefType> {
+		let typ = unsafe {
+			((**self.ptr).GetObjectRefType)(self.ptr, obj.get_obj())
+		};
+		match typ {
+			jobjectRefType::JNIInvalidRefType => None,
+			jobjectRefType::JNILocalRefType => Some(RefType::Local),
+			jobjectRefType::JNIGlobalRefType => Some(RefType::Global),
+			jobjectRefType::JNIWeakGlobalRefType => Some(RefType::Weak),
+		}
+	}
+
 	fn new_local_ref<T: 'a + JObject<'a>>(&self, lobj: &T, _cap: Capability) -> jobject {
 		unsafe { ((**self.ptr).NewLocalRef)(self.ptr, lobj.get_obj()) }
 	}
 
 	fn delete_local_ref<T: 'a + JObject<'a>>(&self, gobj: &T, _cap: &Capability) {
 		assert!(gobj.ref_type() == RefType::Local);
+		self.refs.deleted();
 		unsafe {
 			((**self.ptr).DeleteLocalRef)(self.ptr, gobj.get_obj())
 		}
 	}
 
 	fn new_global_ref<T: 'a + JObject<'a>>(&self, lobj: &T, _cap: Capability) -> jobject {
-		unsafe { ((**self.ptr).NewGlobalRef)(self.ptr, lobj.get_obj()) }
+		let r = unsafe { ((**self.ptr).NewGlobalRef)(self.ptr, lobj.get_obj()) };
+		ref_tracker::global_created(r, RefType::Global);
+		r
 	}
 
 	fn delete_global_ref<T: 'a + JObject<'a>>(&self, gobj: &T, _cap: &Capability) {
 		assert!(gobj.ref_type() == RefType::Global);
+		ref_tracker::global_deleted(gobj.get_obj(), RefType::Global);
 		unsafe {
 			((**self.ptr).DeleteGlobalRef)(self.ptr, gobj.get_obj())
 		}
 	}
 
 	fn new_weak_ref<T: 'a + JObject<'a>>(&self, lobj: &T, _cap: Capability) -> jweak {
-		unsafe { ((**self.ptr).NewWeakGlobalRef)(self.ptr, lobj.get_obj()) }
+		let r = unsafe { ((**self.ptr).NewWeakGlobalRef)(self.ptr, lobj.get_obj()) };
+		ref_tracker::global_created(r as jobject, RefType::Weak);
+		r
 	}
 
 	fn delete_weak_ref<T: 'a + JObject<'a>>(&self, wobj: &T, _cap: &Capability) {
 		assert!(wobj.ref_type() == RefType::Weak);
+		ref_tracker::global_deleted(wobj.get_obj(), RefType::Weak);
 		unsafe {
 			((**self.ptr).DeleteWeakGlobalRef)(self.ptr, wobj.get_obj() as jweak)
 		}
 	}
 
+	/// Counts a reference wrapped by a `JObject`.
+	/// Global and weak refs are counted when they are created.
+	fn track_ref(&self, typ: RefType) {
+		if typ == RefType::Local {
+			// it would be deleted with the frame, but still be usable
+			if self.frames.get() != 0 {
+				panic!("Local ref created through a JavaEnv in one of its local frames, use the frame's JavaEnv.");
+			}
+			self.refs.created();
+		}
+	}
+
+	/// Stops counting a reference the `JObject` gave up.
+	fn untrack_ref(&self, ptr: jobject, typ: RefType) {
+		match typ {
+			RefType::Local => self.refs.deleted(),
+			RefType::Global | RefType::Weak => ref_tracker::global_deleted(ptr, typ),
+			RefType::Borrowed => (),
+		}
+	}
+
+	/// The counts of references of the given type, with the `track-refs` feature.
+	/// Local refs are counted for this `JavaEnv`, global and weak refs for the process.
+	#[cfg(feature = "track-refs")]
+	pub fn ref_counts(&self, typ: RefType) -> RefCounts {
+		match typ {
+			RefType::Local => self.refs.counts(),
+			RefType::Global | RefType::Weak => ref_tracker::global_counts(typ),
+			RefType::Borrowed => RefCounts::default(),
+		}
+	}
+
 	pub fn ensure_local_capacity(&self, capacity: isize, cap: Capability) -> Result<Capability, (JniError, Exception)> {
 		let (err, _) = unsafe {
 			(((**self.ptr).EnsureLocalCapacity)(self.ptr, capacity as jint), cap)
@@ -808,6 +1756,129 @@ impl<'a> JavaEnv<'a> {
 		}
 	}
 
+	/// Checks the arguments and the result type against the method descriptor.
+	fn check_call(&self, method: &JavaMethod, ret: &JavaType, args: &[JValue], cap: Capability) -> Result<Capability, Exception> {
+		if args.len() != method.args.len() {
+			return Err(self.throw_new_by_name(
+				"java/lang/IllegalArgumentException",
+				&format!("{}{}: expected {} arguments, got {}", method.name, method.sig, method.args.len(), args.len()),
+				cap
+			));
+		}
+		for (i, (arg, typ)) in args.iter().zip(method.args.iter()).enumerate() {
+			if !arg.is_compatible(typ) {
+				return Err(self.throw_new_by_name(
+					"java/lang/IllegalArgumentException",
+					&format!("{}{}: argument {} must be {}, got {:?}", method.name, method.sig, i, typ, arg),
+					cap
+				));
+			}
+			if !self.is_value_of(arg, method.arg_classes[i].as_ref(), &cap) {
+				return Err(self.throw_new_by_name(
+					"java/lang/IllegalArgumentException",
+					&format!("{}{}: argument {} is not an instance of {}", method.name, method.sig, i, typ),
+					cap
+				));
+			}
+		}
+		let (ok, cap) = try!(self.is_castable(&method.ret, method.ret_class.as_ref(), ret, cap));
+		if !ok {
+			return Err(self.throw_new_by_name(
+				"java/lang/ClassCastException",
+				&format!("{}{}: result is {}, not {}", method.name, method.sig, method.ret, ret),
+				cap
+			));
+		}
+		Ok(cap)
+	}
+
+	/// Parses a field descriptor of the class `cls` and checks it against
+	/// the field type.  Returns the class of the field, like `resolve_class`.
+	fn check_field(&'a self, cls: &JavaClass, sig: &str, typ: &JavaType, cap: Capability) -> JniResult<Option<JavaClass<'a>>> {
+		let t = match JavaType::parse(sig) {
+			Some(t) => if t.is_reference() || t.is_primitive() {
+				t
+			} else {
+				return Err(self.throw_new_by_name(
+					"java/lang/IllegalArgumentException",
+					&format!("invalid field descriptor {}", sig),
+					cap
+				));
+			},
+			None => return Err(self.throw_new_by_name(
+				"java/lang/IllegalArgumentException",
+				&format!("invalid field descriptor {}", sig),
+				cap
+			)),
+		};
+		let (field_cls, cap) = try!(self.resolve_class(cls, &t, cap));
+		let (ok, cap) = try!(self.is_castable(&t, field_cls.as_ref(), typ, cap));
+		if !ok {
+			return Err(self.throw_new_by_name(
+				"java/lang/ClassCastException",
+				&format!("field is {}, not {}", t, typ),
+				cap
+			));
+		}
+		Ok((field_cls, cap))
+	}
+
+	/// Checks a value before storing it in a field of the class `field_cls`.
+	fn check_field_value(&self, name: &str, sig: &str, field_cls: Option<&JavaClass>, val: &JValue, cap: Capability) -> Result<Capability, Exception> {
+		if self.is_value_of(val, field_cls, &cap) {
+			Ok(cap)
+		} else {
+			Err(self.throw_new_by_name(
+				"java/lang/IllegalArgumentException",
+				&format!("{}: value is not an instance of {}", name, sig),
+				cap
+			))
+		}
+	}
+
+	fn new_object(&self, cls: &JavaClass, ctor: &JavaMethod, args: &[JValue], cap: Capability) -> JniResult<JavaObject> {
+		assert!(cls.jvm() == ctor.cls.jvm());
+		let cap = try!(self.check_call(ctor, &JavaType::Void, args, cap));
+		let args: Vec<jvalue> = args.iter().map(|&v| v.into()).collect();
+		let (obj, _) = unsafe {
+			(((**self.ptr).NewObjectA)(self.ptr, cls.ptr, ctor.id, args.as_ptr()), cap)
+		};
+		// here `cap` is taken, we can't call any Jni methods
+		if obj == 0 as jobject {
+			Err(Exception::new())
+		} else {
+			Ok((unsafe { JObject::from_unsafe(self, obj) }, Capability::new()))
+		}
+	}
+
+	fn register_natives(&self, cls: &JavaClass, methods: &[NativeMethod], cap: Capability) -> Result<Capability, (JniError, Exception)> {
+		let names: Vec<JavaChars> = methods.iter().map(|m| JavaChars::new(&m.name)).collect();
+		let sigs: Vec<JavaChars> = methods.iter().map(|m| JavaChars::new(&m.sig)).collect();
+		let mut natives = Vec::with_capacity(methods.len());
+		for i in 0..methods.len() {
+			natives.push(JNINativeMethod{
+				name: names[i].as_ptr() as *mut ::libc::c_char,
+				signature: sigs[i].as_ptr() as *mut ::libc::c_char,
+				fnPtr: methods[i].fn_ptr,
+			});
+		}
+		let (err, _) = unsafe {
+			(((**self.ptr).RegisterNatives)(self.ptr, cls.ptr, natives.as_ptr(), natives.len() as jint), cap)
+		};
+		// here `cap` is taken, we can't call any Jni methods
+		if err == JniError::JNI_OK {
+			Ok(Capability::new())
+		} else {
+			Err((err, Exception::new()))
+		}
+	}
+
+	fn unregister_natives(&self, cls: &JavaClass, _cap: &Capability) -> JniError {
+		unsafe {
+			((**self.ptr).UnregisterNatives)(self.ptr, cls.ptr)
+		}
+	}
+
 	fn monitor_enter<T: 'a + JObject<'a>>(&self, obj: &T, _cap: &Capability) -> JniError {
 		unsafe {
 			((**self.ptr).MonitorEnter)(self.ptr, obj.get_obj())
@@ -825,6 +1896,86 @@ impl<'a> JavaEnv<'a> {
 		}
 	}
 
+	unsafe fn get_id_gen<I>(&self, fun: extern "C" fn(env: *mut JNIEnvImpl, clazz: jclass, name: *const ::libc::c_char, sig: *const ::libc::c_char) -> *mut I, cls: &JavaClass, name: &str, sig: &str, cap: Capability) -> JniResult<*mut I> {
+		let jname = JavaChars::new(name);
+		let jsig = JavaChars::new(sig);
+		let (id, _) = (fun(self.ptr, cls.ptr, jname.as_ptr(), jsig.as_ptr()), cap);
+		// here `cap` is taken, we can't call any Jni methods
+		if id.is_null() {
+			Err(Exception::new())
+		} else {
+			Ok((id, Capability::new()))
+		}
+	}
+
+	fn get_method_id(&self, cls: &JavaClass, name: &str, sig: &str, cap: Capability) -> JniResult<jmethodID> {
+		unsafe { self.get_id_gen((**self.ptr).GetMethodID, cls, name, sig, cap) }
+	}
+
+	fn get_static_method_id(&self, cls: &JavaClass, name: &str, sig: &str, cap: Capability) -> JniResult<jmethodID> {
+		unsafe { self.get_id_gen((**self.ptr).GetStaticMethodID, cls, name, sig, cap) }
+	}
+
+	fn get_field_id(&self, cls: &JavaClass, name: &str, sig: &str, cap: Capability) -> JniResult<jfieldID> {
+		unsafe { self.get_id_gen((**self.ptr).GetFieldID, cls, name, sig, cap) }
+	}
+
+	fn get_static_field_id(&self, cls: &JavaClass, name: &str, sig: &str, cap: Capability) -> JniResult<jfieldID> {
+		unsafe { self.get_id_gen((**self.ptr).GetStaticFieldID, cls, name, sig, cap) }
+	}
+
+	/// The caller checks the method, the object and the arguments,
+	/// like `JObject::call` does.
+	unsafe fn call_method_gen<T: 'a + JObject<'a>, R>(&self, fun: extern "C" fn(env: *mut JNIEnvImpl, obj: jobject, methodID: jmethodID, args: *const jvalue) -> R, obj: &T, method: &JavaMethod, args: &[JValue], cap: Capability) -> JniResult<R> {
+		assert!(obj.jvm() == method.cls.jvm());
+		let args: Vec<jvalue> = args.iter().map(|&v| v.into()).collect();
+		let (r, _) = (fun(self.ptr, obj.get_obj(), method.id, args.as_ptr()), cap);
+		// here `cap` is taken, we can't call any Jni methods
+		match self.exception_check() {
+			Ok(cap) => Ok((r, cap)),
+			Err(ex) => Err(ex),
+		}
+	}
+
+	unsafe fn call_nonvirtual_method_gen<T: 'a + JObject<'a>, R>(&self, fun: extern "C" fn(env: *mut JNIEnvImpl, obj: jobject, clazz: jclass, methodID: jmethodID, args: *const jvalue) -> R, obj: &T, cls: &JavaClass, method: &JavaMethod, args: &[JValue], cap: Capability) -> JniResult<R> {
+		assert!(obj.jvm() == method.cls.jvm());
+		assert!(cls.jvm() == method.cls.jvm());
+		let args: Vec<jvalue> = args.iter().map(|&v| v.into()).collect();
+		let (r, _) = (fun(self.ptr, obj.get_obj(), cls.ptr, method.id, args.as_ptr()), cap);
+		// here `cap` is taken, we can't call any Jni methods
+		match self.exception_check() {
+			Ok(cap) => Ok((r, cap)),
+			Err(ex) => Err(ex),
+		}
+	}
+
+	unsafe fn call_static_method_gen<R>(&self, fun: extern "C" fn(env: *mut JNIEnvImpl, clazz: jclass, methodID: jmethodID, args: *const jvalue) -> R, cls: &JavaClass, method: &JavaMethod, args: &[JValue], cap: Capability) -> JniResult<R> {
+		assert!(cls.jvm() == method.cls.jvm());
+		let args: Vec<jvalue> = args.iter().map(|&v| v.into()).collect();
+		let (r, _) = (fun(self.ptr, cls.ptr, method.id, args.as_ptr()), cap);
+		// here `cap` is taken, we can't call any Jni methods
+		match self.exception_check() {
+			Ok(cap) => Ok((r, cap)),
+			Err(ex) => Err(ex),
+		}
+	}
+
+	unsafe fn get_field_gen<T: 'a + JObject<'a>, R>(&self, fun: extern "C" fn(env: *mut JNIEnvImpl, obj: jobject, fieldID: jfieldID) -> R, obj: &T, id: jfieldID, _cap: &Capability) -> R {
+		fun(self.ptr, obj.get_obj(), id)
+	}
+
+	unsafe fn set_field_gen<T: 'a + JObject<'a>, V>(&self, fun: extern "C" fn(env: *mut JNIEnvImpl, obj: jobject, fieldID: jfieldID, val: V), obj: &T, id: jfieldID, val: V, _cap: &Capability) {
+		fun(self.ptr, obj.get_obj(), id, val)
+	}
+
+	unsafe fn get_static_field_gen<R>(&self, fun: extern "C" fn(env: *mut JNIEnvImpl, clazz: jclass, fieldID: jfieldID) -> R, cls: &JavaClass, id: jfieldID, _cap: &Capability) -> R {
+		fun(self.ptr, cls.ptr, id)
+	}
+
+	unsafe fn set_static_field_gen<V>(&self, fun: extern "C" fn(env: *mut JNIEnvImpl, clazz: jclass, fieldID: jfieldID, val: V), cls: &JavaClass, id: jfieldID, val: V, _cap: &Capability) {
+		fun(self.ptr, cls.ptr, id, val)
+	}
+
 	fn new_string(&'a self, val: &str, cap: Capability) -> JniResult<JavaString<'a>> {
 		let jval = JavaChars::new(val);
 		let (r, _) = unsafe {
@@ -1119,6 +2270,11 @@ impl<'a> Eq for JavaEnv<'a> {}
 
 impl<'a> Drop for JavaEnv<'a> {
 	fn drop(&mut self) {
+		self.refs.report();
+		if self.borrowed {
+			return;
+		}
+
 		// you can't leave the exception in the air
 		match self.exception_check() {
 			Ok(_) => (),
@@ -1148,6 +2304,9 @@ pub enum RefType {
 	Local,
 	Global,
 	Weak,
+	/// A local reference owned by the JVM, like an argument of a native method.
+	/// It is not deleted when dropped.
+	Borrowed,
 }
 
 pub trait JObject<'a>: Eq + Drop {
@@ -1173,6 +2332,24 @@ pub trait JObject<'a>: Eq + Drop {
 		Some(unsafe { Self::from_unsafe(env, ptr) })
 	}
 
+	/// Wraps a reference owned by the JVM, like an argument of a native method,
+	/// without taking ownership of it.
+	unsafe fn from_borrowed(env: &'a JavaEnv<'a>, ptr: jobject) -> Option<Self> where Self: Sized {
+		if ptr == 0 as jobject {
+			return None;
+		}
+
+		Some(Self::from_unsafe_type(env, ptr, RefType::Borrowed))
+	}
+
+	/// Gives up ownership of the reference, like for returning it from a native method.
+	fn into_raw(self) -> jobject where Self: Sized {
+		let ptr = self.get_obj();
+		self.get_env().untrack_ref(ptr, self.ref_type());
+		mem::forget(self);
+		ptr
+	}
+
 	fn local(&self, cap: Capability) -> JniResult<Self> where Self: 'a + Sized {
 		let r = self.get_env().new_local_ref(self, cap);
 		if r == 0 as jobject {
@@ -1191,13 +2368,9 @@ pub trait JObject<'a>: Eq + Drop {
 		}
 	}
 
-	fn weak(&self, cap: Capability) -> JniResult<Self> where Self: 'a + Sized {
-		let r = self.get_env().new_weak_ref(self, cap);
-		if r == 0 as jobject {
-			Err(Exception::new())
-		} else {
-			Ok((unsafe { JObject::from_unsafe_type(self.get_env(), r, RefType::Weak) }, Capability::new()))
-		}
+	/// Creates a weak global reference, which has to be upgraded to be used.
+	fn weak<T: 'static>(&self, cap: Capability) -> JniResult<WeakRef<T>> where Self: 'a + Sized, T: JRebind<'a, Local=Self> {
+		WeakRef::new(self, cap)
 	}
 
 	fn get_class(&self, cap: &Capability) -> JavaClass<'a> where Self: 'a + Sized {
@@ -1210,14 +2383,12 @@ pub trait JObject<'a>: Eq + Drop {
 			return Err(val.err().unwrap());
 		}
 
-		let obj = val.unwrap();
-		let r = JavaObject{
-			env: obj.0.get_env(),
-			ptr: obj.0.get_obj(),
-			rtype: obj.0.ref_type()
-		};
+		let (obj, cap) = val.unwrap();
+		let env = obj.get_env();
+		let rtype = obj.ref_type();
+		let r = unsafe { JObject::from_unsafe_type(env, obj.into_raw(), rtype) };
 
-		Ok((r, obj.1))
+		Ok((r, cap))
 	}
 
 	fn is_instance_of(&self, cls: &JavaClass, cap: &Capability) -> bool where Self: 'a + Sized {
@@ -1228,9 +2399,65 @@ pub trait JObject<'a>: Eq + Drop {
 		self.get_env().is_null(self, cap)
 	}
 
+	/// Asks the JVM what kind of reference this is, unlike `ref_type`.
+	/// Returns `None` if the reference is invalid, like a deleted one.
+	/// `Borrowed` refs are `Local` for the JVM.
+	fn query_ref_type(&self, cap: &Capability) -> Option<RefType> where Self: 'a + Sized {
+		self.get_env().get_object_ref_type(self, cap)
+	}
+
 	fn monitor(&'a self, cap: &Capability) -> Result<JavaMonitor<'a, Self>, JniError> where Self: Sized {
 		JavaMonitor::new(self, cap)
 	}
+
+	fn call<R: JMethodResult<'a>>(&self, method: &JavaMethod<'a>, args: &[JValue], cap: Capability) -> JniResult<R> where Self: 'a + Sized {
+		if method.is_static {
+			return Err(self.get_env().throw_new_by_name(
+				"java/lang/IllegalArgumentException",
+				&format!("{}{}: method is static", method.name, method.sig),
+				cap
+			));
+		}
+		if !self.is_instance_of(method.cls, &cap) {
+			return Err(self.get_env().throw_new_by_name(
+				"java/lang/IllegalArgumentException",
+				&format!("{}{}: object is not an instance of declaring class", method.name, method.sig),
+				cap
+			));
+		}
+		let cap = try!(self.get_env().check_call(method, &R::java_type(), args, cap));
+		// the call is checked above
+		unsafe { R::call_method(self, method, args, cap) }
+	}
+
+	/// Calls the implementation of the method in the given class,
+	/// bypassing overrides, like `super.method()` does in Java.
+	fn call_nonvirtual<R: JMethodResult<'a>>(&self, cls: &JavaClass<'a>, method: &JavaMethod<'a>, args: &[JValue], cap: Capability) -> JniResult<R> where Self: 'a + Sized {
+		if method.is_static {
+			return Err(self.get_env().throw_new_by_name(
+				"java/lang/IllegalArgumentException",
+				&format!("{}{}: method is static", method.name, method.sig),
+				cap
+			));
+		}
+		if !self.is_instance_of(cls, &cap) {
+			return Err(self.get_env().throw_new_by_name(
+				"java/lang/IllegalArgumentException",
+				&format!("{}{}: object is not an instance of the class", method.name, method.sig),
+				cap
+			));
+		}
+		if !cls.is_assignable_from(method.cls, &cap) {
+			return Err(self.get_env().throw_new_by_name(
+				"java/lang/IllegalArgumentException",
+				&format!("{}{}: class does not have the method", method.name, method.sig),
+				cap
+			));
+		}
+		let cap = try!(self.get_env().check_call(method, &R::java_type(), args, cap));
+		// the call is checked above
+		unsafe { R::call_nonvirtual_method(self, cls, method, args, cap) }
+	}
 }
 
 #[derive(Debug)]
@@ -1263,18 +2490,44 @@ impl<'a, T: 'a + JObject<'a>> Drop for JavaMonitor<'a, T> {
 
 // pub trait JArray<'a, T: 'a + JObject<'a>>: JObject<'a> {}
 
+/// In debug builds, or with the `track-refs` feature, checks that the JVM
+/// agrees with the stored `RefType` before the reference is deleted, so
+/// that a double delete panics instead of crashing the JVM.
+#[cfg(any(debug_assertions, feature = "track-refs"))]
+fn check_ref_type<'a, T: 'a + JObject<'a>>(obj: &T) {
+	// a second panic while unwinding would abort
+	if obj.get_obj() == 0 as jobject || thread::panicking() {
+		return;
+	}
+	// `GetObjectRefType` can't be called with a pending exception
+	if let Ok(cap) = obj.get_env().exception_check() {
+		let actual = obj.get_env().get_object_ref_type(obj, &cap);
+		if actual != Some(obj.ref_type()) {
+			panic!("Deleting a {:?} reference, but the JVM sees it as {:?}.", obj.ref_type(), actual);
+		}
+	}
+}
+
+#[cfg(not(any(debug_assertions, feature = "track-refs")))]
+fn check_ref_type<'a, T: 'a + JObject<'a>>(_obj: &T) {}
+
 macro_rules! impl_jobject(
 	($cls:ident, $native:ident) => (
 		impl<'a> Drop for $cls<'a> {
 			fn drop(&mut self) {
+				if self.ref_type() == RefType::Borrowed {
+					return;
+				}
+
+				check_ref_type(self);
 				let env = self.get_env();
-				match env.exception_check() {
-					Ok(cap) => match self.ref_type() {
-						RefType::Local => env.delete_local_ref(self, &cap),
-						RefType::Global => env.delete_global_ref(self, &cap),
-						RefType::Weak => env.delete_weak_ref(self, &cap),
-					},
-					Err(_) => panic!("Can't call JNI method with pending exception."),
+				// deleting references is safe with a pending exception
+				let cap = Capability::new();
+				match self.ref_type() {
+					RefType::Local => env.delete_local_ref(self, &cap),
+					RefType::Global => env.delete_global_ref(self, &cap),
+					RefType::Weak => env.delete_weak_ref(self, &cap),
+					RefType::Borrowed => unreachable!(),
 				}
 			}
 		}
@@ -1305,6 +2558,7 @@ macro_rules! impl_jobject(
 			}
 
 			unsafe fn from_unsafe_type(env: &'a JavaEnv<'a>, ptr: jobject, typ: RefType) -> $cls<'a> {
+				env.track_ref(typ);
 				$cls{
 					env: env,
 					ptr: ptr as $native,
@@ -1313,6 +2567,10 @@ macro_rules! impl_jobject(
 			}
 		}
 
+		impl<'a, 'b> JRebind<'a> for $cls<'b> {
+			type Local = $cls<'a>;
+		}
+
 		impl<'a> JArrayElem<'a> for $cls<'a> {
 			fn new_array(env: &'a JavaEnv<'a>, len: usize, val: &Self, cap: Capability) -> JniResult<JavaArray<'a, Self>> {
 				let cls = val.get_class(&cap);
@@ -1365,27 +2623,648 @@ pub struct JavaClass<'a> {
 	rtype: RefType,
 }
 
-impl_jobject!(JavaClass, jclass);
+impl_jobject!(JavaClass, jclass);
+
+impl<'a> JavaClass<'a> {
+	pub fn get_super(&'a self, cap: &Capability) -> Option<JavaClass<'a>> {
+		self.env.get_super_class(self, cap)
+	}
+
+	pub fn is_assignable_from(&self, cls: &JavaClass<'a>, cap: &Capability) -> bool {
+		self.env.is_assignable_from(self, cls, cap)
+	}
+
+	pub fn alloc(&'a self, cap: Capability) -> JniResult<JavaObject<'a>> {
+		self.env.alloc_object(self, cap)
+	}
+
+	/// Creates a new object by calling the constructor with the given descriptor.
+	pub fn new_object(&'a self, sig: &str, args: &[JValue], cap: Capability) -> JniResult<JavaObject<'a>> {
+		let (ctor, cap) = try!(JavaMethod::new(self, "<init>", sig, cap));
+		self.env.new_object(self, &ctor, args, cap)
+	}
+
+	pub fn find<'b>(env: &'b JavaEnv<'b>, name: &str, cap: Capability) -> JniResult<JavaClass<'b>> {
+		env.find_class(name, cap)
+	}
+
+	pub fn define<'b, T: 'b + JObject<'b>>(env: &'b JavaEnv<'b>, name: &str, loader: &T, buf: &[u8], cap: Capability) -> JniResult<JavaClass<'b>> {
+		env.define_class(name, loader, buf, cap)
+	}
+
+	/// Binds native methods of this class to Rust functions.
+	pub fn register_natives(&self, methods: &[NativeMethod], cap: Capability) -> Result<Capability, (JniError, Exception)> {
+		self.env.register_natives(self, methods, cap)
+	}
+
+	/// Unbinds all native methods of this class.
+	pub fn unregister_natives(&self, cap: &Capability) -> JniError {
+		self.env.unregister_natives(self, cap)
+	}
+
+	pub fn call_static<R: JMethodResult<'a>>(&self, method: &JavaMethod<'a>, args: &[JValue], cap: Capability) -> JniResult<R> {
+		if !method.is_static {
+			return Err(self.env.throw_new_by_name(
+				"java/lang/IllegalArgumentException",
+				&format!("{}{}: method is not static", method.name, method.sig),
+				cap
+			));
+		}
+		if !self.is_assignable_from(method.cls, &cap) {
+			return Err(self.env.throw_new_by_name(
+				"java/lang/IllegalArgumentException",
+				&format!("{}{}: class does not have the method", method.name, method.sig),
+				cap
+			));
+		}
+		let cap = try!(self.env.check_call(method, &R::java_type(), args, cap));
+		// the call is checked above
+		unsafe { R::call_static_method(self, method, args, cap) }
+	}
+
+	pub fn get_static_field<T: JFieldType<'a>>(&self, name: &str, sig: &str, cap: Capability) -> JniResult<T> {
+		let (_, cap) = try!(self.env.check_field(self, sig, &T::java_type(), cap));
+		let (id, cap) = try!(self.env.get_static_field_id(self, name, sig, cap));
+		// the type is checked above
+		let r = unsafe { T::get_static_field(self, id, &cap) };
+		Ok((r, cap))
+	}
+
+	pub fn set_static_field<T: JFieldType<'a>>(&self, name: &str, sig: &str, val: &T, cap: Capability) -> JniResult<()> {
+		let (field_cls, cap) = try!(self.env.check_field(self, sig, &T::java_type(), cap));
+		let (id, cap) = try!(self.env.get_static_field_id(self, name, sig, cap));
+		let cap = try!(self.env.check_field_value(name, sig, field_cls.as_ref(), &val.value(), cap));
+		// the type is checked above
+		unsafe { T::set_static_field(self, id, val, &cap) };
+		Ok(((), cap))
+	}
+}
+
+
+/// A method of a Java class, resolved once by name and descriptor.
+#[derive(Debug)]
+#[allow(raw_pointer_derive)]
+pub struct JavaMethod<'a> {
+	cls: &'a JavaClass<'a>,
+	id: jmethodID,
+	name: String,
+	sig: String,
+	args: Vec<JavaType>,
+	ret: JavaType,
+	/// The classes of the reference types, see `JavaEnv::resolve_class`.
+	arg_classes: Vec<Option<JavaClass<'a>>>,
+	ret_class: Option<JavaClass<'a>>,
+	is_static: bool,
+}
+
+impl<'a> JavaMethod<'a> {
+	pub fn new(cls: &'a JavaClass<'a>, name: &str, sig: &str, cap: Capability) -> JniResult<JavaMethod<'a>> {
+		Self::new_gen(cls, name, sig, false, cap)
+	}
+
+	pub fn new_static(cls: &'a JavaClass<'a>, name: &str, sig: &str, cap: Capability) -> JniResult<JavaMethod<'a>> {
+		Self::new_gen(cls, name, sig, true, cap)
+	}
+
+	fn new_gen(cls: &'a JavaClass<'a>, name: &str, sig: &str, is_static: bool, cap: Capability) -> JniResult<JavaMethod<'a>> {
+		let (args, ret) = match JavaType::parse(sig) {
+			Some(JavaType::Method(args, ret)) => (args, *ret),
+			_ => return Err(cls.env.throw_new_by_name(
+				"java/lang/IllegalArgumentException",
+				&format!("invalid method descriptor {}", sig),
+				cap
+			)),
+		};
+		let (id, mut cap) = if is_static {
+			try!(cls.env.get_static_method_id(cls, name, sig, cap))
+		} else {
+			try!(cls.env.get_method_id(cls, name, sig, cap))
+		};
+		let mut arg_classes = vec![];
+		for arg in &args {
+			let (arg_cls, c) = try!(cls.env.resolve_class(cls, arg, cap));
+			arg_classes.push(arg_cls);
+			cap = c;
+		}
+		let (ret_class, cap) = try!(cls.env.resolve_class(cls, &ret, cap));
+		Ok((JavaMethod{
+			cls: cls,
+			id: id,
+			name: name.to_string(),
+			sig: sig.to_string(),
+			args: args,
+			ret: ret,
+			arg_classes: arg_classes,
+			ret_class: ret_class,
+			is_static: is_static,
+		}, cap))
+	}
+
+	pub fn class(&self) -> &'a JavaClass<'a> {
+		self.cls
+	}
+
+	pub fn name(&self) -> &str {
+		&self.name
+	}
+
+	pub fn signature(&self) -> &str {
+		&self.sig
+	}
+
+	pub fn arg_types(&self) -> &[JavaType] {
+		&self.args
+	}
+
+	pub fn return_type(&self) -> &JavaType {
+		&self.ret
+	}
+
+	pub fn is_static(&self) -> bool {
+		self.is_static
+	}
+}
+
+/// A type that can be returned from a Java method.
+///
+/// Objects are returned as `Option`, since Java methods can return `null`.
+///
+/// The methods of this trait call the method without any checks,
+/// so they are unsafe: use `JObject::call` and `JavaClass::call_static`.
+pub trait JMethodResult<'a>: JTyped + Sized {
+	/// The caller must check the arguments and the result type, like `JObject::call` does.
+	unsafe fn call_method<T: 'a + JObject<'a>>(obj: &T, method: &JavaMethod<'a>, args: &[JValue], cap: Capability) -> JniResult<Self>;
+	/// The caller must also check that the object is an instance of the class,
+	/// like `JObject::call_nonvirtual` does.
+	unsafe fn call_nonvirtual_method<T: 'a + JObject<'a>>(obj: &T, cls: &JavaClass<'a>, method: &JavaMethod<'a>, args: &[JValue], cap: Capability) -> JniResult<Self>;
+	/// The caller must check the arguments and the result type, like `JavaClass::call_static` does.
+	unsafe fn call_static_method(cls: &JavaClass<'a>, method: &JavaMethod<'a>, args: &[JValue], cap: Capability) -> JniResult<Self>;
+}
+
+impl<'a> JMethodResult<'a> for () {
+	unsafe fn call_method<T: 'a + JObject<'a>>(obj: &T, method: &JavaMethod<'a>, args: &[JValue], cap: Capability) -> JniResult<Self> {
+		let env = obj.get_env();
+		env.call_method_gen((**env.ptr).CallVoidMethodA, obj, method, args, cap)
+	}
+
+	unsafe fn call_nonvirtual_method<T: 'a + JObject<'a>>(obj: &T, cls: &JavaClass<'a>, method: &JavaMethod<'a>, args: &[JValue], cap: Capability) -> JniResult<Self> {
+		let env = obj.get_env();
+		env.call_nonvirtual_method_gen((**env.ptr).CallNonvirtualVoidMethodA, obj, cls, method, args, cap)
+	}
+
+	unsafe fn call_static_method(cls: &JavaClass<'a>, method: &JavaMethod<'a>, args: &[JValue], cap: Capability) -> JniResult<Self> {
+		let env = cls.get_env();
+		env.call_static_method_gen((**env.ptr).CallStaticVoidMethodA, cls, method, args, cap)
+	}
+}
+
+impl<'a, R: 'a + JObject<'a> + JTyped> JMethodResult<'a> for Option<R> {
+	unsafe fn call_method<T: 'a + JObject<'a>>(obj: &T, method: &JavaMethod<'a>, args: &[JValue], cap: Capability) -> JniResult<Self> {
+		let env = obj.get_env();
+		let (r, cap) = try!(env.call_method_gen((**env.ptr).CallObjectMethodA, obj, method, args, cap));
+		Ok((JObject::from(env, r), cap))
+	}
+
+	unsafe fn call_nonvirtual_method<T: 'a + JObject<'a>>(obj: &T, cls: &JavaClass<'a>, method: &JavaMethod<'a>, args: &[JValue], cap: Capability) -> JniResult<Self> {
+		let env = obj.get_env();
+		let (r, cap) = try!(env.call_nonvirtual_method_gen((**env.ptr).CallNonvirtualObjectMethodA, obj, cls, method, args, cap));
+		Ok((JObject::from(env, r), cap))
+	}
+
+	unsafe fn call_static_method(cls: &JavaClass<'a>, method: &JavaMethod<'a>, args: &[JValue], cap: Capability) -> JniResult<Self> {
+		let env = cls.get_env();
+		let (r, cap) = try!(env.call_static_method_gen((**env.ptr).CallStaticObjectMethodA, cls, method, args, cap));
+		Ok((JObject::from(env, r), cap))
+	}
+}
+
+macro_rules! impl_jmethod_result(
+	($rtype:ty, $call:ident, $call_nonvirtual:ident, $call_static:ident) => (
+		impl<'a> JMethodResult<'a> for $rtype {
+			unsafe fn call_method<T: 'a + JObject<'a>>(obj: &T, method: &JavaMethod<'a>, args: &[JValue], cap: Capability) -> JniResult<Self> {
+				let env = obj.get_env();
+				let (r, cap) = try!(env.call_method_gen((**env.ptr).$call, obj, method, args, cap));
+				Ok((r.repr(), cap))
+			}
+
+			unsafe fn call_nonvirtual_method<T: 'a + JObject<'a>>(obj: &T, cls: &JavaClass<'a>, method: &JavaMethod<'a>, args: &[JValue], cap: Capability) -> JniResult<Self> {
+				let env = obj.get_env();
+				let (r, cap) = try!(env.call_nonvirtual_method_gen((**env.ptr).$call_nonvirtual, obj, cls, method, args, cap));
+				Ok((r.repr(), cap))
+			}
+
+			unsafe fn call_static_method(cls: &JavaClass<'a>, method: &JavaMethod<'a>, args: &[JValue], cap: Capability) -> JniResult<Self> {
+				let env = cls.get_env();
+				let (r, cap) = try!(env.call_static_method_gen((**env.ptr).$call_static, cls, method, args, cap));
+				Ok((r.repr(), cap))
+			}
+		}
+	);
+);
+
+impl_jmethod_result!(bool, CallBooleanMethodA, CallNonvirtualBooleanMethodA, CallStaticBooleanMethodA);
+impl_jmethod_result!(u8, CallByteMethodA, CallNonvirtualByteMethodA, CallStaticByteMethodA);
+impl_jmethod_result!(char, CallCharMethodA, CallNonvirtualCharMethodA, CallStaticCharMethodA);
+impl_jmethod_result!(i16, CallShortMethodA, CallNonvirtualShortMethodA, CallStaticShortMethodA);
+impl_jmethod_result!(i32, CallIntMethodA, CallNonvirtualIntMethodA, CallStaticIntMethodA);
+impl_jmethod_result!(i64, CallLongMethodA, CallNonvirtualLongMethodA, CallStaticLongMethodA);
+impl_jmethod_result!(f32, CallFloatMethodA, CallNonvirtualFloatMethodA, CallStaticFloatMethodA);
+impl_jmethod_result!(f64, CallDoubleMethodA, CallNonvirtualDoubleMethodA, CallStaticDoubleMethodA);
+
+/// A type that can be stored in a Java field.
+///
+/// Objects are stored as `Option`, since Java fields can hold `null`.
+///
+/// The methods of this trait access the field without any checks, so the
+/// field has to be of this type, and the object has to have the field:
+/// use `JavaField` and `JavaClass::get_static_field` instead.
+pub trait JFieldType<'a>: JTyped + Sized {
+	/// The value as a `JValue`, to check it before storing it.
+	fn value(&self) -> JValue;
+	unsafe fn get_field<T: 'a + JObject<'a>>(obj: &T, id: jfieldID, cap: &Capability) -> Self;
+	unsafe fn set_field<T: 'a + JObject<'a>>(obj: &T, id: jfieldID, val: &Self, cap: &Capability);
+	unsafe fn get_static_field(cls: &JavaClass<'a>, id: jfieldID, cap: &Capability) -> Self;
+	unsafe fn set_static_field(cls: &JavaClass<'a>, id: jfieldID, val: &Self, cap: &Capability);
+}
+
+impl<'a, R: 'a + JObject<'a> + JTyped> JFieldType<'a> for Option<R> {
+	fn value(&self) -> JValue {
+		match *self {
+			None => JValue::Null,
+			Some(ref v) => JValue::from(v),
+		}
+	}
+
+	unsafe fn get_field<T: 'a + JObject<'a>>(obj: &T, id: jfieldID, cap: &Capability) -> Self {
+		let env = obj.get_env();
+		let r = env.get_field_gen((**env.ptr).GetObjectField, obj, id, cap);
+		JObject::from(env, r)
+	}
+
+	unsafe fn set_field<T: 'a + JObject<'a>>(obj: &T, id: jfieldID, val: &Self, cap: &Capability) {
+		let env = obj.get_env();
+		let val = match *val {
+			None => 0 as jobject,
+			Some(ref v) => v.get_obj(),
+		};
+		env.set_field_gen((**env.ptr).SetObjectField, obj, id, val, cap)
+	}
+
+	unsafe fn get_static_field(cls: &JavaClass<'a>, id: jfieldID, cap: &Capability) -> Self {
+		let env = cls.get_env();
+		let r = env.get_static_field_gen((**env.ptr).GetStaticObjectField, cls, id, cap);
+		JObject::from(env, r)
+	}
+
+	unsafe fn set_static_field(cls: &JavaClass<'a>, id: jfieldID, val: &Self, cap: &Capability) {
+		let env = cls.get_env();
+		let obj = match *val {
+			None => 0 as jobject,
+			Some(ref v) => v.get_obj(),
+		};
+		env.set_static_field_gen((**env.ptr).SetStaticObjectField, cls, id, obj, cap)
+	}
+}
+
+macro_rules! impl_jfield_type(
+	($rtype:ty, $get:ident, $set:ident, $get_static:ident, $set_static:ident) => (
+		impl<'a> JFieldType<'a> for $rtype {
+			fn value(&self) -> JValue {
+				JValue::from(*self)
+			}
+
+			unsafe fn get_field<T: 'a + JObject<'a>>(obj: &T, id: jfieldID, cap: &Capability) -> Self {
+				let env = obj.get_env();
+				let r = env.get_field_gen((**env.ptr).$get, obj, id, cap);
+				r.repr()
+			}
+
+			unsafe fn set_field<T: 'a + JObject<'a>>(obj: &T, id: jfieldID, val: &Self, cap: &Capability) {
+				let env = obj.get_env();
+				let val = <<$rtype as RPrimitive>::JType as JPrimitive>::from(*val);
+				env.set_field_gen((**env.ptr).$set, obj, id, val, cap)
+			}
+
+			unsafe fn get_static_field(cls: &JavaClass<'a>, id: jfieldID, cap: &Capability) -> Self {
+				let env = cls.get_env();
+				let r = env.get_static_field_gen((**env.ptr).$get_static, cls, id, cap);
+				r.repr()
+			}
+
+			unsafe fn set_static_field(cls: &JavaClass<'a>, id: jfieldID, val: &Self, cap: &Capability) {
+				let env = cls.get_env();
+				let val = <<$rtype as RPrimitive>::JType as JPrimitive>::from(*val);
+				env.set_static_field_gen((**env.ptr).$set_static, cls, id, val, cap)
+			}
+		}
+	);
+);
+
+impl_jfield_type!(bool, GetBooleanField, SetBooleanField, GetStaticBooleanField, SetStaticBooleanField);
+impl_jfield_type!(u8, GetByteField, SetByteField, GetStaticByteField, SetStaticByteField);
+impl_jfield_type!(char, GetCharField, SetCharField, GetStaticCharField, SetStaticCharField);
+impl_jfield_type!(i16, GetShortField, SetShortField, GetStaticShortField, SetStaticShortField);
+impl_jfield_type!(i32, GetIntField, SetIntField, GetStaticIntField, SetStaticIntField);
+impl_jfield_type!(i64, GetLongField, SetLongField, GetStaticLongField, SetStaticLongField);
+impl_jfield_type!(f32, GetFloatField, SetFloatField, GetStaticFloatField, SetStaticFloatField);
+impl_jfield_type!(f64, GetDoubleField, SetDoubleField, GetStaticDoubleField, SetStaticDoubleField);
+
+/// A raw JNI type that can be passed to or returned from a native method.
+pub trait JNativeType {
+	fn java_type() -> JavaType;
+
+	/// The value returned to Java when a native method throws.
+	fn default_value() -> Self;
+}
+
+macro_rules! impl_jnative_type(
+	($ntype:ty, $jtype:expr, $default:expr) => (
+		impl JNativeType for $ntype {
+			fn java_type() -> JavaType {
+				$jtype
+			}
+
+			fn default_value() -> Self {
+				$default
+			}
+		}
+	);
+);
+
+impl_jnative_type!((), JavaType::Void, ());
+impl_jnative_type!(jboolean, JavaType::Boolean, JNI_FALSE);
+impl_jnative_type!(jbyte, JavaType::Byte, 0);
+impl_jnative_type!(jchar, JavaType::Char, 0);
+impl_jnative_type!(jshort, JavaType::Short, 0);
+impl_jnative_type!(jint, JavaType::Int, 0);
+impl_jnative_type!(jlong, JavaType::Long, 0);
+impl_jnative_type!(jfloat, JavaType::Float, 0.0);
+impl_jnative_type!(jdouble, JavaType::Double, 0.0);
+impl_jnative_type!(jobject, JavaType::class("java/lang/Object"), 0 as jobject);
+
+/// A function that can implement a native method.
+///
+/// The first two arguments are the `JNIEnv` and the object (or the
+/// class, for static methods) the method is called on.
+pub trait JNativeFn {
+	fn arg_types() -> Vec<JavaType>;
+	fn return_type() -> JavaType;
+	fn as_ptr(&self) -> *mut jvoid;
+}
+
+macro_rules! impl_jnative_fn(
+	($($arg:ident),*) => (
+		impl<R: JNativeType $(, $arg: JNativeType)*> JNativeFn for extern "system" fn(*mut JNIEnvImpl, jobject $(, $arg)*) -> R {
+			fn arg_types() -> Vec<JavaType> {
+				vec![$($arg::java_type()),*]
+			}
+
+			fn return_type() -> JavaType {
+				R::java_type()
+			}
+
+			fn as_ptr(&self) -> *mut jvoid {
+				*self as usize as *mut jvoid
+			}
+		}
+	);
+);
+
+impl_jnative_fn!();
+impl_jnative_fn!(A1);
+impl_jnative_fn!(A1, A2);
+impl_jnative_fn!(A1, A2, A3);
+impl_jnative_fn!(A1, A2, A3, A4);
+impl_jnative_fn!(A1, A2, A3, A4, A5);
+impl_jnative_fn!(A1, A2, A3, A4, A5, A6);
+impl_jnative_fn!(A1, A2, A3, A4, A5, A6, A7);
+impl_jnative_fn!(A1, A2, A3, A4, A5, A6, A7, A8);
+
+/// A Rust function to be bound to a native method of a Java class.
+#[derive(Debug, Clone)]
+#[allow(raw_pointer_derive)]
+pub struct NativeMethod {
+	name: String,
+	sig: String,
+	fn_ptr: *mut jvoid,
+}
+
+impl NativeMethod {
+	/// Creates a native method binding.
+	/// Returns `None` if the descriptor does not match the function type.
+	///
+	/// Function items have to be cast to a function pointer, like
+	/// `add as extern "system" fn(*mut JNIEnvImpl, jobject, jint, jint) -> jint`.
+	pub fn new<F: JNativeFn>(name: &str, sig: &str, fun: F) -> Option<NativeMethod> {
+		let (args, ret) = match JavaType::parse(sig) {
+			Some(JavaType::Method(args, ret)) => (args, ret),
+			_ => return None,
+		};
+		let fn_args = F::arg_types();
+		if args.len() != fn_args.len() || !ret.is_compatible(&F::return_type()) {
+			return None;
+		}
+		for (arg, fn_arg) in args.iter().zip(fn_args.iter()) {
+			if !arg.is_compatible(fn_arg) {
+				return None;
+			}
+		}
+		Some(unsafe { Self::new_unchecked(name, sig, fun.as_ptr()) })
+	}
+
+	/// Creates a native method binding without checking the function type.
+	pub unsafe fn new_unchecked(name: &str, sig: &str, fn_ptr: *mut jvoid) -> NativeMethod {
+		NativeMethod{
+			name: name.to_string(),
+			sig: sig.to_string(),
+			fn_ptr: fn_ptr,
+		}
+	}
+
+	pub fn name(&self) -> &str {
+		&self.name
+	}
+
+	pub fn signature(&self) -> &str {
+		&self.sig
+	}
+}
+
+/// A Rust type that can be an argument of an exported native method.
+/// Used by `#[jni_export]`.
+pub trait JNativeArg<'a>: Sized {
+	type Raw: JNativeType;
+
+	unsafe fn from_native(env: &'a JavaEnv<'a>, raw: Self::Raw) -> Self;
+}
+
+/// A Rust type that can be returned from an exported native method.
+/// Used by `#[jni_export]`.
+pub trait JNativeResult {
+	type Raw: JNativeType;
+
+	fn into_native(self, env: &JavaEnv) -> Self::Raw;
+}
+
+macro_rules! impl_jnative_primitive(
+	($rtype:ty, $ntype:ty) => (
+		impl<'a> JNativeArg<'a> for $rtype {
+			type Raw = $ntype;
+
+			unsafe fn from_native(_env: &'a JavaEnv<'a>, raw: $ntype) -> Self {
+				raw.repr()
+			}
+		}
+
+		impl JNativeResult for $rtype {
+			type Raw = $ntype;
+
+			fn into_native(self, _env: &JavaEnv) -> $ntype {
+				<$ntype as JPrimitive>::from(self)
+			}
+		}
+	);
+);
+
+impl_jnative_primitive!(bool, jboolean);
+impl_jnative_primitive!(u8, jbyte);
+impl_jnative_primitive!(char, jchar);
+impl_jnative_primitive!(i16, jshort);
+impl_jnative_primitive!(i32, jint);
+impl_jnative_primitive!(i64, jlong);
+impl_jnative_primitive!(f32, jfloat);
+impl_jnative_primitive!(f64, jdouble);
+
+/// Panics on `null`, use `Option` for nullable arguments.
+impl<'a, T: 'a + JObject<'a>> JNativeArg<'a> for T {
+	type Raw = jobject;
+
+	unsafe fn from_native(env: &'a JavaEnv<'a>, raw: jobject) -> Self {
+		match T::from_borrowed(env, raw) {
+			Some(obj) => obj,
+			None => panic!("Unexpected null argument."),
+		}
+	}
+}
+
+impl<'a, T: 'a + JObject<'a>> JNativeArg<'a> for Option<T> {
+	type Raw = jobject;
+
+	unsafe fn from_native(env: &'a JavaEnv<'a>, raw: jobject) -> Self {
+		T::from_borrowed(env, raw)
+	}
+}
+
+impl JNativeResult for () {
+	type Raw = ();
+
+	fn into_native(self, _env: &JavaEnv) {}
+}
+
+impl<'a, T: 'a + JObject<'a>> JNativeResult for T {
+	type Raw = jobject;
+
+	fn into_native(self, _env: &JavaEnv) -> jobject {
+		self.into_raw()
+	}
+}
+
+impl<'a, T: 'a + JObject<'a>> JNativeResult for Option<T> {
+	type Raw = jobject;
+
+	fn into_native(self, _env: &JavaEnv) -> jobject {
+		match self {
+			Some(obj) => obj.into_raw(),
+			None => 0 as jobject,
+		}
+	}
+}
+
+/// On `Err` the exception stays pending and is thrown to the Java caller.
+impl<T: JNativeResult> JNativeResult for JniResult<T> {
+	type Raw = T::Raw;
+
+	fn into_native(self, env: &JavaEnv) -> T::Raw {
+		match self {
+			Ok((val, _)) => val.into_native(env),
+			Err(_) => T::Raw::default_value(),
+		}
+	}
+}
+
+/// On `Err` the error is thrown to the Java caller.
+impl<T: JNativeResult, E: ToJavaException> JNativeResult for Result<T, E> {
+	type Raw = T::Raw;
+
+	fn into_native(self, env: &JavaEnv) -> T::Raw {
+		match self {
+			Ok(val) => val.into_native(env),
+			Err(err) => {
+				// an exception which is already pending is kept
+				if let Ok(cap) = env.exception_check() {
+					env.throw_error(&err, cap);
+				}
+				T::Raw::default_value()
+			}
+		}
+	}
+}
+
+/// A field of a Java class, resolved once by name and descriptor.
+#[derive(Debug)]
+#[allow(raw_pointer_derive)]
+pub struct JavaField<'a, T: JFieldType<'a>> {
+	cls: &'a JavaClass<'a>,
+	id: jfieldID,
+	name: String,
+	sig: String,
+	/// The class of the field, see `JavaEnv::resolve_class`.
+	field_cls: Option<JavaClass<'a>>,
+	phantom: PhantomData<T>,
+}
+
+impl<'a, T: JFieldType<'a>> JavaField<'a, T> {
+	pub fn new(cls: &'a JavaClass<'a>, name: &str, sig: &str, cap: Capability) -> JniResult<JavaField<'a, T>> {
+		let (field_cls, cap) = try!(cls.env.check_field(cls, sig, &T::java_type(), cap));
+		let (id, cap) = try!(cls.env.get_field_id(cls, name, sig, cap));
+		Ok((JavaField{
+			cls: cls,
+			id: id,
+			name: name.to_string(),
+			sig: sig.to_string(),
+			field_cls: field_cls,
+			phantom: PhantomData::<T>,
+		}, cap))
+	}
 
-impl<'a> JavaClass<'a> {
-	pub fn get_super(&'a self, cap: &Capability) -> Option<JavaClass<'a>> {
-		self.env.get_super_class(self, cap)
+	pub fn class(&self) -> &'a JavaClass<'a> {
+		self.cls
 	}
 
-	pub fn is_assignable_from(&self, cls: &JavaClass<'a>, cap: &Capability) -> bool {
-		self.env.is_assignable_from(self, cls, cap)
+	pub fn name(&self) -> &str {
+		&self.name
 	}
 
-	pub fn alloc(&'a self, cap: Capability) -> JniResult<JavaObject<'a>> {
-		self.env.alloc_object(self, cap)
+	pub fn signature(&self) -> &str {
+		&self.sig
 	}
 
-	pub fn find<'b>(env: &'b JavaEnv<'b>, name: &str, cap: Capability) -> JniResult<JavaClass<'b>> {
-		env.find_class(name, cap)
+	pub fn get<O: 'a + JObject<'a>>(&self, obj: &O, cap: &Capability) -> T {
+		assert!(obj.jvm() == self.cls.jvm());
+		assert!(obj.is_instance_of(self.cls, cap));
+		// the type is checked by `JavaField::new`
+		unsafe { T::get_field(obj, self.id, cap) }
 	}
 
-	pub fn define<'b, T: 'b + JObject<'b>>(env: &'b JavaEnv<'b>, name: &str, loader: &T, buf: &[u8], cap: Capability) -> JniResult<JavaClass<'b>> {
-		env.define_class(name, loader, buf, cap)
+	/// Stores a value in the field of an object, throwing
+	/// `IllegalArgumentException` if it isn't an instance of the field's class.
+	pub fn set<O: 'a + JObject<'a>>(&self, obj: &O, val: &T, cap: Capability) -> JniResult<()> {
+		assert!(obj.jvm() == self.cls.jvm());
+		assert!(obj.is_instance_of(self.cls, &cap));
+		let cap = try!(self.cls.env.check_field_value(&self.name, &self.sig, self.field_cls.as_ref(), &val.value(), cap));
+		// the type is checked by `JavaField::new`
+		unsafe { T::set_field(obj, self.id, val, &cap) };
+		Ok(((), cap))
 	}
 }
 
@@ -1408,6 +3287,17 @@ impl<'a> JavaThrowable<'a> {
 		env.throw_new(cls, msg, cap)
 	}
 
+	/// Clears the pending exception and returns it if it is an instance
+	/// of `cls`, otherwise it stays pending.
+	pub fn catch<'b, C: JExceptionClass + ?Sized>(env: &'b JavaEnv<'b>, cls: &C, exn: Exception) -> Result<(JavaThrowable<'b>, Capability), Exception> {
+		cls.catch(env, exn)
+	}
+
+	/// Throws a Rust error as a Java exception.
+	pub fn throw_error<'b, E: ToJavaException + ?Sized>(env: &'b JavaEnv<'b>, err: &E, cap: Capability) -> Exception {
+		env.throw_error(err, cap)
+	}
+
 	pub fn check<'b>(env: &'b JavaEnv<'b>) -> Result<Capability, Exception> {
 		env.exception_check()
 	}
@@ -1427,6 +3317,248 @@ impl<'a> JavaThrowable<'a> {
 	pub fn fatal_error<'b>(env: &'b JavaEnv<'b>, msg: &str) -> ! {
 		env.fatal_error(msg)
 	}
+
+	/// Runs the body of a native method, turning a panic into an exception
+	/// of class `cls`, like `java/lang/RuntimeException`, which is thrown
+	/// to the Java caller.  Returns the result converted for the JVM, or a
+	/// default value on panic.
+	pub fn catch_panic<'b, R: JNativeResult, F: FnOnce() -> R>(env: &'b JavaEnv<'b>, cls: &str, f: F) -> R::Raw {
+		env.catch_panic(cls, f)
+	}
+}
+
+thread_local!(static PANIC_LOCATION: RefCell<Option<String>> = RefCell::new(None));
+thread_local!(static CATCHING_PANICS: Cell<usize> = Cell::new(0));
+static PANIC_HOOK_INIT: Once = Once::new();
+
+/// The panic payload does not have the location, so a hook saves it for
+/// panics inside `catch_panic`.  The previous hook, like the one set by
+/// the application, still runs for every panic.
+fn install_panic_hook() {
+	PANIC_HOOK_INIT.call_once(|| {
+		let prev = panic::take_hook();
+		panic::set_hook(Box::new(move |info| {
+			if CATCHING_PANICS.with(|n| n.get()) != 0 {
+				let location = info.location().map(|l| format!("{}:{}:{}", l.file(), l.line(), l.column()));
+				PANIC_LOCATION.with(|loc| *loc.borrow_mut() = location);
+			}
+			prev(info);
+		}));
+	});
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+	let msg = match payload.downcast_ref::<&str>() {
+		Some(msg) => msg.to_string(),
+		None => match payload.downcast_ref::<String>() {
+			Some(msg) => msg.clone(),
+			None => "Box<Any>".to_string(),
+		},
+	};
+	match PANIC_LOCATION.with(|loc| loc.borrow_mut().take()) {
+		Some(location) => format!("Rust panic at {}: {}", location, msg),
+		None => format!("Rust panic: {}", msg),
+	}
+}
+
+/// A Rust error that can be thrown as a Java exception.
+pub trait ToJavaException {
+	/// The class of the exception, like `java/lang/RuntimeException`.
+	/// It must have a constructor taking the message.
+	fn exception_class(&self) -> &str {
+		"java/lang/RuntimeException"
+	}
+
+	fn exception_message(&self) -> String;
+
+	/// The error to set as the cause of the exception, with its own
+	/// `source()` chain.
+	fn exception_cause(&self) -> Option<&dyn Error> {
+		None
+	}
+}
+
+impl ToJavaException for io::Error {
+	fn exception_class(&self) -> &str {
+		"java/io/IOException"
+	}
+
+	fn exception_message(&self) -> String {
+		self.to_string()
+	}
+
+	/// The wrapped error, since `source()` skips it.
+	fn exception_cause(&self) -> Option<&dyn Error> {
+		match self.get_ref() {
+			Some(err) => Some(err),
+			None => None,
+		}
+	}
+}
+
+impl ToJavaException for Box<dyn Error + Send + Sync> {
+	fn exception_message(&self) -> String {
+		self.to_string()
+	}
+
+	fn exception_cause(&self) -> Option<&dyn Error> {
+		self.source()
+	}
+}
+
+/// A class of exceptions to catch: a `JavaClass`, or a class name
+/// like `java/io/IOException`.
+pub trait JExceptionClass {
+	fn catch<'a>(&self, env: &'a JavaEnv<'a>, exn: Exception) -> Result<(JavaThrowable<'a>, Capability), Exception>;
+}
+
+impl<'b> JExceptionClass for JavaClass<'b> {
+	fn catch<'a>(&self, env: &'a JavaEnv<'a>, exn: Exception) -> Result<(JavaThrowable<'a>, Capability), Exception> {
+		env.catch_exception(self, exn)
+	}
+}
+
+impl JExceptionClass for str {
+	fn catch<'a>(&self, env: &'a JavaEnv<'a>, exn: Exception) -> Result<(JavaThrowable<'a>, Capability), Exception> {
+		env.catch_exception_by_name(self, exn)
+	}
+}
+
+/// Catching of exceptions of a class from a `JniResult`:
+///
+/// ```ignore
+/// let (res, cap) = try!(file.call::<()>(&open, &[], cap).catch(&env, "java/io/FileNotFoundException"));
+/// match res {
+///     Ok(()) => ...,
+///     Err(ex) => ..., // the file was not found
+/// }
+/// ```
+///
+/// Exceptions of other classes stay pending.
+pub trait JCatch<T> {
+	fn catch<'a, C: JExceptionClass + ?Sized>(self, env: &'a JavaEnv<'a>, cls: &C) -> JniResult<Result<T, JavaThrowable<'a>>>;
+}
+
+impl<T> JCatch<T> for JniResult<T> {
+	fn catch<'a, C: JExceptionClass + ?Sized>(self, env: &'a JavaEnv<'a>, cls: &C) -> JniResult<Result<T, JavaThrowable<'a>>> {
+		match self {
+			Ok((val, cap)) => Ok((Ok(val), cap)),
+			Err(exn) => {
+				let (ex, cap) = try!(cls.catch(env, exn));
+				Ok((Err(ex), cap))
+			}
+		}
+	}
+}
+
+/// The methods used to read a `JavaException`, resolved once for it and its causes.
+struct ExceptionMethods<'a> {
+	get_name: JavaMethod<'a>,
+	get_message: JavaMethod<'a>,
+	get_stack_trace: JavaMethod<'a>,
+	get_cause: JavaMethod<'a>,
+	get_class_name: JavaMethod<'a>,
+	get_method_name: JavaMethod<'a>,
+	get_file_name: JavaMethod<'a>,
+	get_line_number: JavaMethod<'a>,
+}
+
+/// A frame of the stack trace of a `JavaException`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JavaStackFrame {
+	pub class_name: String,
+	pub method_name: String,
+	pub file_name: Option<String>,
+	/// Negative if unknown, `-2` for native methods.
+	pub line_number: i32,
+}
+
+impl fmt::Display for JavaStackFrame {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		try!(write!(f, "{}.{}", self.class_name, self.method_name));
+		match (&self.file_name, self.line_number) {
+			(_, -2) => write!(f, "(Native Method)"),
+			(&None, _) => write!(f, "(Unknown Source)"),
+			(&Some(ref file), n) if n < 0 => write!(f, "({})", file),
+			(&Some(ref file), n) => write!(f, "({}:{})", file, n),
+		}
+	}
+}
+
+/// A Java exception read into Rust data.
+///
+/// Displays like `Throwable.toString()`, the alternate form `{:#}`
+/// adds the stack trace and the causes, like `printStackTrace()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JavaException {
+	/// The class name, like `java.lang.RuntimeException`.
+	pub class_name: String,
+	pub message: Option<String>,
+	pub stack_trace: Vec<JavaStackFrame>,
+	pub cause: Option<Box<JavaException>>,
+}
+
+impl JavaException {
+	/// Reads and clears the pending exception.  If it can't be read,
+	/// like when the JVM is out of memory, it is left pending and
+	/// returned as `Err`.
+	pub fn take<'a>(env: &'a JavaEnv<'a>, exn: Exception) -> JniResult<JavaException> {
+		env.take_exception(exn)
+	}
+
+	/// Reads a throwable.
+	pub fn from_throwable<'a>(ex: &JavaThrowable<'a>, cap: Capability) -> JniResult<JavaException> {
+		ex.get_env().read_exception(ex, cap)
+	}
+}
+
+impl fmt::Display for JavaException {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self.message {
+			None => try!(write!(f, "{}", self.class_name)),
+			Some(ref msg) => try!(write!(f, "{}: {}", self.class_name, msg)),
+		}
+		if f.alternate() {
+			for frame in &self.stack_trace {
+				try!(write!(f, "\n\tat {}", frame));
+			}
+			if let Some(ref cause) = self.cause {
+				try!(write!(f, "\nCaused by: {:#}", cause));
+			}
+		}
+		Ok(())
+	}
+}
+
+impl Error for JavaException {
+	fn source(&self) -> Option<&(dyn Error + 'static)> {
+		match self.cause {
+			None => None,
+			Some(ref cause) => Some(&**cause),
+		}
+	}
+}
+
+/// An error thrown as `IllegalArgumentException`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IllegalArgument(pub String);
+
+impl fmt::Display for IllegalArgument {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}", self.0)
+	}
+}
+
+impl Error for IllegalArgument {}
+
+impl ToJavaException for IllegalArgument {
+	fn exception_class(&self) -> &str {
+		"java/lang/IllegalArgumentException"
+	}
+
+	fn exception_message(&self) -> String {
+		self.0.clone()
+	}
 }
 
 pub struct JavaString<'a> {
@@ -1550,11 +3682,9 @@ impl<'a> JavaDirectByteBuffer<'a> {
 
 impl<'a> Drop for JavaDirectByteBuffer<'a> {
 	fn drop(&mut self) {
-		let env = self.get_env();
-		match env.exception_check() {
-			Ok(cap) => env.delete_local_ref(self, &cap),
-			Err(_) => panic!("Can't call JNI method with pending exception."),
-		}
+		check_ref_type(self);
+		// deleting references is safe with a pending exception
+		self.get_env().delete_local_ref(self, &Capability::new())
 	}
 }
 
@@ -1585,6 +3715,7 @@ impl<'a> JObject<'a> for JavaDirectByteBuffer<'a> {
 
 	unsafe fn from_unsafe_type(env: &'a JavaEnv<'a>, ptr: jobject, typ: RefType) -> JavaDirectByteBuffer<'a> {
 		assert!(typ == RefType::Local);
+		env.track_ref(typ);
 		JavaDirectByteBuffer{
 			env: env,
 			ptr: ptr,
@@ -1593,6 +3724,10 @@ impl<'a> JObject<'a> for JavaDirectByteBuffer<'a> {
 	}
 }
 
+impl<'a, 'b> JRebind<'a> for JavaDirectByteBuffer<'b> {
+	type Local = JavaDirectByteBuffer<'a>;
+}
+
 impl<'a> JArrayElem<'a> for JavaDirectByteBuffer<'a> {
 	fn new_array(env: &'a JavaEnv<'a>, len: usize, val: &Self, cap: Capability) -> JniResult<JavaArray<'a, Self>> {
 		let cls = val.get_class(&cap);
@@ -1785,14 +3920,19 @@ impl<'a, T: 'a + JArrayElem<'a>> JavaArray<'a, T> {
 
 impl<'a, T: 'a + JArrayElem<'a>> Drop for JavaArray<'a, T> {
 	fn drop(&mut self) {
+		if self.ref_type() == RefType::Borrowed {
+			return;
+		}
+
+		check_ref_type(self);
 		let env = self.get_env();
-		match env.exception_check() {
-			Ok(cap) => match self.ref_type() {
-				RefType::Local => env.delete_local_ref(self, &cap),
-				RefType::Global => env.delete_global_ref(self, &cap),
-				RefType::Weak => env.delete_weak_ref(self, &cap),
-			},
-			Err(_) => panic!("Can't call JNI method with pending exception."),
+		// deleting references is safe with a pending exception
+		let cap = Capability::new();
+		match self.ref_type() {
+			RefType::Local => env.delete_local_ref(self, &cap),
+			RefType::Global => env.delete_global_ref(self, &cap),
+			RefType::Weak => env.delete_weak_ref(self, &cap),
+			RefType::Borrowed => unreachable!(),
 		}
 	}
 }
@@ -1838,6 +3978,7 @@ impl<'a, T: 'a + JArrayElem<'a>> JObject<'a> for JavaArray<'a, T> {
 	}
 
 	unsafe fn from_unsafe_type(env: &'a JavaEnv<'a>, ptr: jobject, typ: RefType) -> JavaArray<'a, T> {
+		env.track_ref(typ);
 		JavaArray{
 			env: env,
 			ptr: ptr as jarray,
@@ -1847,10 +3988,180 @@ impl<'a, T: 'a + JArrayElem<'a>> JObject<'a> for JavaArray<'a, T> {
 	}
 }
 
+/// Rebinds the lifetime of a type, like `JavaClass<'static>` to `JavaClass<'a>`.
+pub trait JRebind<'a> {
+	type Local;
+}
+
+macro_rules! impl_jrebind_primitive(
+	($rtype:ty) => (
+		impl<'a> JRebind<'a> for $rtype {
+			type Local = $rtype;
+		}
+	);
+);
+
+impl_jrebind_primitive!(bool);
+impl_jrebind_primitive!(u8);
+impl_jrebind_primitive!(char);
+impl_jrebind_primitive!(i16);
+impl_jrebind_primitive!(i32);
+impl_jrebind_primitive!(i64);
+impl_jrebind_primitive!(f32);
+impl_jrebind_primitive!(f64);
+
+impl<'a, 'b, T: 'b + JArrayElem<'b> + JRebind<'a>> JRebind<'a> for JavaArray<'b, T> where T::Local: 'a + JArrayElem<'a> {
+	type Local = JavaArray<'a, T::Local>;
+}
+
+/// A global reference that is not bound to a `JavaEnv`,
+/// so it can be stored and sent to other threads.
+///
+/// `T` is the type of the object with the `'static` lifetime,
+/// like `GlobalRef<JavaClass<'static>>`.
+pub struct GlobalRef<T> {
+	jvm: JavaVM,
+	ptr: jobject,
+	phantom: PhantomData<T>,
+}
+
+// global references are valid in any thread
+unsafe impl<T> Send for GlobalRef<T> {}
+unsafe impl<T> Sync for GlobalRef<T> {}
+
+impl<T: 'static> GlobalRef<T> {
+	/// Creates a global reference to the object.
+	pub fn new<'a, O: 'a + JObject<'a>>(obj: &O, cap: Capability) -> JniResult<GlobalRef<T>> where T: JRebind<'a, Local=O> {
+		let ptr = obj.get_env().new_global_ref(obj, cap);
+		if ptr == 0 as jobject {
+			return Err(Exception::new());
+		}
+		let res = GlobalRef{
+			jvm: obj.jvm().unowned(),
+			ptr: ptr,
+			phantom: PhantomData,
+		};
+		Ok((res, Capability::new()))
+	}
+
+	pub fn jvm(&self) -> &JavaVM {
+		&self.jvm
+	}
+
+	/// Borrows the object in the current thread.
+	pub fn as_local<'a>(&'a self, env: &'a JavaEnv<'a>) -> T::Local where T: JRebind<'a>, T::Local: JObject<'a> {
+		assert!(*env.jvm() == self.jvm);
+		unsafe { JObject::from_unsafe_type(env, self.ptr, RefType::Borrowed) }
+	}
+}
+
+impl<T> fmt::Debug for GlobalRef<T> {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "GlobalRef({:?})", self.ptr)
+	}
+}
+
+impl<T> Drop for GlobalRef<T> {
+	fn drop(&mut self) {
+		// `DeleteGlobalRef` is fine with a pending exception, and if the
+		// thread can't be attached, the reference is leaked
+		let ptr = self.ptr;
+		let _ = unsafe {
+			self.jvm.with_raw_env(|env| {
+				ref_tracker::global_deleted(ptr, RefType::Global);
+				((**env).DeleteGlobalRef)(env, ptr)
+			})
+		};
+	}
+}
+
+/// A weak global reference, which does not keep the object from being
+/// garbage collected.  Like `GlobalRef`, it is not bound to a `JavaEnv`.
+///
+/// The object can only be used through `upgrade`, which fails
+/// if it was already collected.
+pub struct WeakRef<T> {
+	jvm: JavaVM,
+	ptr: jweak,
+	phantom: PhantomData<T>,
+}
+
+// weak global references are valid in any thread
+unsafe impl<T> Send for WeakRef<T> {}
+unsafe impl<T> Sync for WeakRef<T> {}
+
+impl<T: 'static> WeakRef<T> {
+	/// Creates a weak global reference to the object.
+	pub fn new<'a, O: 'a + JObject<'a>>(obj: &O, cap: Capability) -> JniResult<WeakRef<T>> where T: JRebind<'a, Local=O> {
+		let ptr = obj.get_env().new_weak_ref(obj, cap);
+		if ptr == 0 as jweak {
+			return Err(Exception::new());
+		}
+		let res = WeakRef{
+			jvm: obj.jvm().unowned(),
+			ptr: ptr,
+			phantom: PhantomData,
+		};
+		Ok((res, Capability::new()))
+	}
+
+	pub fn jvm(&self) -> &JavaVM {
+		&self.jvm
+	}
+
+	/// Gets a local reference to the object.
+	/// Returns `None` if it was garbage collected.
+	pub fn upgrade<'a>(&self, env: &'a JavaEnv<'a>, _cap: &Capability) -> Option<T::Local> where T: JRebind<'a>, T::Local: JObject<'a> {
+		assert!(*env.jvm() == self.jvm);
+		let ptr = unsafe { ((**env.ptr).NewLocalRef)(env.ptr, self.ptr) };
+		JObject::from(env, ptr)
+	}
+
+	/// Gets a global reference to the object.
+	/// Returns `None` if it was garbage collected.
+	pub fn upgrade_global(&self, env: &JavaEnv, _cap: &Capability) -> Option<GlobalRef<T>> {
+		assert!(*env.jvm() == self.jvm);
+		let ptr = unsafe { ((**env.ptr).NewGlobalRef)(env.ptr, self.ptr) };
+		if ptr == 0 as jobject {
+			return None;
+		}
+		ref_tracker::global_created(ptr, RefType::Global);
+		Some(GlobalRef{
+			jvm: self.jvm.unowned(),
+			ptr: ptr,
+			phantom: PhantomData,
+		})
+	}
+}
+
+impl<T> fmt::Debug for WeakRef<T> {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "WeakRef({:?})", self.ptr)
+	}
+}
+
+impl<T> Drop for WeakRef<T> {
+	fn drop(&mut self) {
+		// like `GlobalRef`, this works with a pending exception,
+		// and leaks the reference if the thread can't be attached
+		let ptr = self.ptr;
+		let _ = unsafe {
+			self.jvm.with_raw_env(|env| {
+				ref_tracker::global_deleted(ptr as jobject, RefType::Weak);
+				((**env).DeleteWeakGlobalRef)(env, ptr)
+			})
+		};
+	}
+}
+
 #[cfg(test)]
 mod tests {
 	use super::*;
 	use super::super::native::*;
+	use super::super::signature::JavaType;
+	use ::std::io;
+	use ::std::error::Error;
+	use ::std::panic::{self, AssertUnwindSafe};
 
 	#[test]
 	fn test_JavaVMOption() {
@@ -1903,10 +4214,721 @@ mod tests {
 		let _ = env.exception_clear(ex);
 	}
 
+	fn test_JavaMethod(jvm: &JavaVM) {
+		let (env, cap) = jvm.get_env().unwrap();
+		let (cls, cap) = JavaClass::find(&env, "java/lang/String", cap).unwrap();
+		let (st, cap) = JavaString::new(&env, "Hello, world!", cap).unwrap();
+
+		let (len, cap) = JavaMethod::new(&cls, "length", "()I", cap).unwrap();
+		assert!(len.name() == "length");
+		assert!(len.signature() == "()I");
+		let (r, cap) = st.call::<i32>(&len, &[], cap).unwrap();
+		assert!(r == 13);
+
+		let (empty, cap) = JavaMethod::new(&cls, "isEmpty", "()Z", cap).unwrap();
+		let (r, cap) = st.call::<bool>(&empty, &[], cap).unwrap();
+		assert!(!r);
+
+		let (upper, cap) = JavaMethod::new(&cls, "toUpperCase", "()Ljava/lang/String;", cap).unwrap();
+		let (r, cap) = st.call::<Option<JavaString>>(&upper, &[], cap).unwrap();
+		assert!(r.unwrap().to_str(&cap).unwrap() == "HELLO, WORLD!");
+
+		let (char_at, cap) = JavaMethod::new(&cls, "charAt", "(I)C", cap).unwrap();
+		let (r, cap) = st.call::<char>(&char_at, &[JValue::from(4)], cap).unwrap();
+		assert!(r == 'o');
+
+		let (suffix, cap) = JavaString::new(&env, "!!", cap).unwrap();
+		let (concat, cap) = JavaMethod::new(&cls, "concat", "(Ljava/lang/String;)Ljava/lang/String;", cap).unwrap();
+		let (r, cap) = st.call::<Option<JavaString>>(&concat, &[JValue::from(&suffix)], cap).unwrap();
+		assert!(r.unwrap().to_str(&cap).unwrap() == "Hello, world!!!");
+
+		let r = st.call::<char>(&char_at, &[JValue::from(100)], cap);
+		assert!(r.is_err());
+		let _ = env.exception_clear(r.err().unwrap());
+		let cap = env.exception_check().unwrap();
+
+		let (cce, cap) = JavaClass::find(&env, "java/lang/ClassCastException", cap).unwrap();
+		let (iae, mut cap) = JavaClass::find(&env, "java/lang/IllegalArgumentException", cap).unwrap();
+		for args in &[vec![], vec![JValue::from(4i64)], vec![JValue::Null]] {
+			let r = st.call::<char>(&char_at, &args[..], cap);
+			assert!(r.is_err());
+			let (ex, exn) = JavaThrowable::occured(&env).err().unwrap();
+			cap = JavaThrowable::clear(&env, exn);
+			assert!(ex.is_instance_of(&iae, &cap));
+		}
+
+		let r = st.call::<i32>(&char_at, &[JValue::from(4)], cap);
+		assert!(r.is_err());
+		let (ex, exn) = JavaThrowable::occured(&env).err().unwrap();
+		let cap = JavaThrowable::clear(&env, exn);
+		assert!(ex.is_instance_of(&cce, &cap));
+
+		// the receiver must be an instance of the method's class
+		let (object, cap) = JavaClass::find(&env, "java/lang/Object", cap).unwrap();
+		let (obj, cap) = object.new_object("()V", &[], cap).unwrap();
+		let r = obj.call::<i32>(&len, &[], cap);
+		assert!(r.is_err());
+		let (ex, exn) = JavaThrowable::occured(&env).err().unwrap();
+		let cap = JavaThrowable::clear(&env, exn);
+		assert!(ex.is_instance_of(&iae, &cap));
+
+		let r = JavaMethod::new(&cls, "charAt", "(I", cap);
+		assert!(r.is_err());
+		let (ex, exn) = JavaThrowable::occured(&env).err().unwrap();
+		let cap = JavaThrowable::clear(&env, exn);
+		assert!(ex.is_instance_of(&iae, &cap));
+		assert!(char_at.arg_types() == [JavaType::Int]);
+		assert!(*char_at.return_type() == JavaType::Char);
+
+		let m = JavaMethod::new(&cls, "noSuchMethod", "()V", cap);
+		assert!(m.is_err());
+		let _ = env.exception_clear(m.err().unwrap());
+	}
+
+	fn test_JavaMethod_nonvirtual(jvm: &JavaVM) {
+		let (env, cap) = jvm.get_env().unwrap();
+		let (object, cap) = JavaClass::find(&env, "java/lang/Object", cap).unwrap();
+		let (string, cap) = JavaClass::find(&env, "java/lang/String", cap).unwrap();
+		let (st, cap) = JavaString::new(&env, "Hello, world!", cap).unwrap();
+
+		let (to_string, cap) = JavaMethod::new(&object, "toString", "()Ljava/lang/String;", cap).unwrap();
+		let (r, cap) = st.call::<Option<JavaString>>(&to_string, &[], cap).unwrap();
+		assert!(r.unwrap().to_str(&cap).unwrap() == "Hello, world!");
+		let (r, cap) = st.call_nonvirtual::<Option<JavaString>>(&object, &to_string, &[], cap).unwrap();
+		assert!(r.unwrap().to_str(&cap).unwrap().starts_with("java.lang.String@"));
+
+		let (hash_code, cap) = JavaMethod::new(&string, "hashCode", "()I", cap).unwrap();
+		let (h1, cap) = st.call::<i32>(&hash_code, &[], cap).unwrap();
+		let (h2, cap) = st.call_nonvirtual::<i32>(&string, &hash_code, &[], cap).unwrap();
+		assert!(h1 == h2);
+
+		let (char_at, cap) = JavaMethod::new(&string, "charAt", "(I)C", cap).unwrap();
+		let (r, cap) = st.call_nonvirtual::<char>(&string, &char_at, &[JValue::from(1)], cap).unwrap();
+		assert!(r == 'e');
+
+		let r = st.call_nonvirtual::<char>(&string, &char_at, &[], cap);
+		assert!(r.is_err());
+		let cap = env.exception_clear(r.err().unwrap());
+
+		// the object must be of the class, the class must have the method,
+		// and static methods can't be called on objects
+		let (iae, cap) = JavaClass::find(&env, "java/lang/IllegalArgumentException", cap).unwrap();
+		let (integer, cap) = JavaClass::find(&env, "java/lang/Integer", cap).unwrap();
+		let r = st.call_nonvirtual::<Option<JavaString>>(&integer, &to_string, &[], cap);
+		assert!(r.is_err());
+		let (ex, exn) = JavaThrowable::occured(&env).err().unwrap();
+		let cap = JavaThrowable::clear(&env, exn);
+		assert!(ex.is_instance_of(&iae, &cap));
+
+		let r = st.call_nonvirtual::<i32>(&object, &hash_code, &[], cap);
+		assert!(r.is_err());
+		let (ex, exn) = JavaThrowable::occured(&env).err().unwrap();
+		let cap = JavaThrowable::clear(&env, exn);
+		assert!(ex.is_instance_of(&iae, &cap));
+
+		let (value_of, cap) = JavaMethod::new_static(&string, "valueOf", "(I)Ljava/lang/String;", cap).unwrap();
+		let r = st.call_nonvirtual::<Option<JavaString>>(&string, &value_of, &[JValue::from(1)], cap);
+		assert!(r.is_err());
+		let (ex, exn) = JavaThrowable::occured(&env).err().unwrap();
+		let cap = JavaThrowable::clear(&env, exn);
+		assert!(ex.is_instance_of(&iae, &cap));
+
+		let r = st.call::<Option<JavaString>>(&value_of, &[JValue::from(1)], cap);
+		assert!(r.is_err());
+		let (ex, exn) = JavaThrowable::occured(&env).err().unwrap();
+		let cap = JavaThrowable::clear(&env, exn);
+		assert!(ex.is_instance_of(&iae, &cap));
+
+		let r = string.call_static::<i32>(&hash_code, &[], cap);
+		assert!(r.is_err());
+		let (ex, exn) = JavaThrowable::occured(&env).err().unwrap();
+		let cap = JavaThrowable::clear(&env, exn);
+		assert!(ex.is_instance_of(&iae, &cap));
+	}
+
+	fn test_JavaField(jvm: &JavaVM) {
+		let (env, cap) = jvm.get_env().unwrap();
+		let (cls, cap) = JavaClass::find(&env, "java/lang/String", cap).unwrap();
+		let (st, cap) = JavaString::new(&env, "Hello, world!", cap).unwrap();
+
+		let (hash, cap) = JavaField::<i32>::new(&cls, "hash", "I", cap).unwrap();
+		assert!(hash.name() == "hash");
+		assert!(hash.get(&st, &cap) == 0);
+
+		let (hash_code, cap) = JavaMethod::new(&cls, "hashCode", "()I", cap).unwrap();
+		let (r, cap) = st.call::<i32>(&hash_code, &[], cap).unwrap();
+		assert!(hash.get(&st, &cap) == r);
+
+		let (_, cap) = hash.set(&st, &42, cap).unwrap();
+		assert!(hash.get(&st, &cap) == 42);
+		let (r, cap) = st.call::<i32>(&hash_code, &[], cap).unwrap();
+		assert!(r == 42);
+
+		let f = JavaField::<i64>::new(&cls, "noSuchField", "J", cap);
+		assert!(f.is_err());
+		let cap = env.exception_clear(f.err().unwrap());
+
+		let (cce, cap) = JavaClass::find(&env, "java/lang/ClassCastException", cap).unwrap();
+		let f = JavaField::<i64>::new(&cls, "hash", "I", cap);
+		assert!(f.is_err());
+		let (ex, exn) = JavaThrowable::occured(&env).err().unwrap();
+		let cap = JavaThrowable::clear(&env, exn);
+		assert!(ex.is_instance_of(&cce, &cap));
+
+		// the classes of reference fields are checked too
+		let (stream, cap) = JavaClass::find(&env, "java/io/ByteArrayOutputStream", cap).unwrap();
+		let (out, cap) = stream.new_object("()V", &[], cap).unwrap();
+		let f = JavaField::<Option<JavaString>>::new(&stream, "buf", "[B", cap);
+		assert!(f.is_err());
+		let (ex, exn) = JavaThrowable::occured(&env).err().unwrap();
+		let cap = JavaThrowable::clear(&env, exn);
+		assert!(ex.is_instance_of(&cce, &cap));
+
+		let (buf, cap) = JavaField::<Option<JavaObject>>::new(&stream, "buf", "[B", cap).unwrap();
+		let b = buf.get(&out, &cap);
+		assert!(b.is_some());
+		let (_, cap) = buf.set(&out, &b, cap).unwrap();
+
+		let (object, cap) = JavaClass::find(&env, "java/lang/Object", cap).unwrap();
+		let (to_string, cap) = JavaMethod::new(&object, "toString", "()Ljava/lang/String;", cap).unwrap();
+		let (o, cap) = st.call::<Option<JavaObject>>(&to_string, &[], cap).unwrap();
+		let (iae, cap) = JavaClass::find(&env, "java/lang/IllegalArgumentException", cap).unwrap();
+		let r = buf.set(&out, &o, cap);
+		assert!(r.is_err());
+		let (ex, exn) = JavaThrowable::occured(&env).err().unwrap();
+		let cap = JavaThrowable::clear(&env, exn);
+		assert!(ex.is_instance_of(&iae, &cap));
+		assert!(buf.get(&out, &cap) == b);
+	}
+
+	fn test_JavaClass_new_object(jvm: &JavaVM) {
+		let (env, cap) = jvm.get_env().unwrap();
+		let (cls, cap) = JavaClass::find(&env, "java/lang/StringBuilder", cap).unwrap();
+		let (obj, cap) = cls.new_object("()V", &[], cap).unwrap();
+		assert!(obj.is_instance_of(&cls, &cap));
+
+		let (to_string, cap) = JavaMethod::new(&cls, "toString", "()Ljava/lang/String;", cap).unwrap();
+		let (r, cap) = obj.call::<Option<JavaString>>(&to_string, &[], cap).unwrap();
+		assert!(r.unwrap().to_str(&cap).unwrap() == "");
+
+		let (iae, cap) = JavaClass::find(&env, "java/lang/IllegalArgumentException", cap).unwrap();
+		let r = cls.new_object("(I)V", &[], cap);
+		assert!(r.is_err());
+		let (ex, exn) = JavaThrowable::occured(&env).err().unwrap();
+		let cap = JavaThrowable::clear(&env, exn);
+		assert!(ex.is_instance_of(&iae, &cap));
+
+		let (obj, cap) = cls.new_object("(I)V", &[JValue::from(16)], cap).unwrap();
+		let (capacity, cap) = JavaMethod::new(&cls, "capacity", "()I", cap).unwrap();
+		let (r, cap) = obj.call::<i32>(&capacity, &[], cap).unwrap();
+		assert!(r == 16);
+
+		let r = cls.new_object("(I)V", &[JValue::from(true)], cap);
+		assert!(r.is_err());
+		let _ = env.exception_clear(r.err().unwrap());
+		let cap = env.exception_check().unwrap();
+
+		let (iae, cap) = JavaClass::find(&env, "java/lang/IllegalArgumentException", cap).unwrap();
+		let (list, cap) = JavaClass::find(&env, "java/util/ArrayList", cap).unwrap();
+		let r = list.new_object("(I)V", &[JValue::from(-1)], cap);
+		assert!(r.is_err());
+		let (ex, exn) = JavaThrowable::occured(&env).err().unwrap();
+		let cap = JavaThrowable::clear(&env, exn);
+		assert!(ex.is_instance_of(&iae, &cap));
+
+		let r = cls.new_object("(Lno/Such;)V", &[], cap);
+		assert!(r.is_err());
+		let _ = env.exception_clear(r.err().unwrap());
+	}
+
 	#[test]
-	fn test_JavaVM() {
-		use std::thread;
+	fn test_JniVersion() {
+		assert!(JniVersion::from_raw(0x00010008) == Some(JniVersion::JNI_VERSION_1_8));
+		assert!(JniVersion::from_raw(0x00010003) == Some(JniVersion::JNI_VERSION_1_2));
+		assert!(JniVersion::from_raw(0x00110000) == Some(JniVersion::JNI_VERSION_10));
+		assert!(JniVersion::from_raw(0x7fff0000) == Some(JniVersion::JNI_VERSION_21));
+		assert!(JniVersion::from_raw(0x00010000) == None);
+	}
+
+	#[test]
+	fn test_JValue() {
+		unsafe {
+			assert!(jvalue::from(JValue::from(true)).z == JNI_TRUE);
+			assert!(jvalue::from(JValue::from(-7i32)).i == -7);
+			assert!(jvalue::from(JValue::from(1i64 << 40)).j == 1i64 << 40);
+			assert!(jvalue::from(JValue::from(0.5f64)).d == 0.5);
+			assert!(jvalue::from(JValue::from('x')).c == 'x' as jchar);
+			assert!(jvalue::from(JValue::Null).l == 0 as jobject);
+		}
+	}
+
+	fn test_JavaClass_static(jvm: &JavaVM) {
+		let (env, cap) = jvm.get_env().unwrap();
+		let (system, cap) = JavaClass::find(&env, "java/lang/System", cap).unwrap();
+		let (millis, cap) = JavaMethod::new_static(&system, "currentTimeMillis", "()J", cap).unwrap();
+		assert!(millis.is_static());
+		let (r, cap) = system.call_static::<i64>(&millis, &[], cap).unwrap();
+		assert!(r > 0);
+
+		let (sep, cap) = JavaMethod::new_static(&system, "lineSeparator", "()Ljava/lang/String;", cap).unwrap();
+		let (r, cap) = system.call_static::<Option<JavaString>>(&sep, &[], cap).unwrap();
+		assert!(r.unwrap().to_str(&cap).unwrap() == "\n");
+
+		let (key, cap) = JavaString::new(&env, "line.separator", cap).unwrap();
+		let (prop, cap) = JavaMethod::new_static(&system, "getProperty", "(Ljava/lang/String;)Ljava/lang/String;", cap).unwrap();
+		let (r, cap) = system.call_static::<Option<JavaString>>(&prop, &[JValue::from(&key)], cap).unwrap();
+		assert!(r.unwrap().to_str(&cap).unwrap() == "\n");
+
+		let (integer, cap) = JavaClass::find(&env, "java/lang/Integer", cap).unwrap();
+		let (max, cap) = integer.get_static_field::<i32>("MAX_VALUE", "I", cap).unwrap();
+		assert!(max == ::std::i32::MAX);
+
+		// the classes of the arguments and of the result are checked
+		let (cce, cap) = JavaClass::find(&env, "java/lang/ClassCastException", cap).unwrap();
+		let (iae, cap) = JavaClass::find(&env, "java/lang/IllegalArgumentException", cap).unwrap();
+		let (value_of, cap) = JavaMethod::new_static(&integer, "valueOf", "(I)Ljava/lang/Integer;", cap).unwrap();
+		let r = integer.call_static::<Option<JavaString>>(&value_of, &[JValue::from(1)], cap);
+		assert!(r.is_err());
+		let (ex, exn) = JavaThrowable::occured(&env).err().unwrap();
+		let cap = JavaThrowable::clear(&env, exn);
+		assert!(ex.is_instance_of(&cce, &cap));
+
+		let (one, cap) = integer.call_static::<Option<JavaObject>>(&value_of, &[JValue::from(1)], cap).unwrap();
+		let one = one.unwrap();
+		let r = system.call_static::<Option<JavaString>>(&prop, &[JValue::from(&one)], cap);
+		assert!(r.is_err());
+		let (ex, exn) = JavaThrowable::occured(&env).err().unwrap();
+		let cap = JavaThrowable::clear(&env, exn);
+		assert!(ex.is_instance_of(&iae, &cap));
+
+		let (boolean, cap) = JavaClass::find(&env, "java/lang/Boolean", cap).unwrap();
+		let (t, cap) = boolean.get_static_field::<Option<JavaObject>>("TRUE", "Ljava/lang/Boolean;", cap).unwrap();
+		let (cce, cap) = JavaClass::find(&env, "java/lang/ClassCastException", cap).unwrap();
+		let r = boolean.get_static_field::<Option<JavaString>>("TRUE", "Ljava/lang/Boolean;", cap);
+		assert!(r.is_err());
+		let (ex, exn) = JavaThrowable::occured(&env).err().unwrap();
+		let cap = JavaThrowable::clear(&env, exn);
+		assert!(ex.is_instance_of(&cce, &cap));
+		let (o, cap) = system.call_static::<Option<JavaObject>>(&sep, &[], cap).unwrap();
+		let r = boolean.set_static_field("TRUE", "Ljava/lang/Boolean;", &o, cap);
+		assert!(r.is_err());
+		let (ex, exn) = JavaThrowable::occured(&env).err().unwrap();
+		let cap = JavaThrowable::clear(&env, exn);
+		assert!(ex.is_instance_of(&iae, &cap));
+		assert!(t.unwrap().is_instance_of(&boolean, &cap));
+
+		let (thread, cap) = JavaClass::find(&env, "java/lang/Thread", cap).unwrap();
+		let (n, cap) = thread.get_static_field::<i32>("threadInitNumber", "I", cap).unwrap();
+		let (_, cap) = thread.set_static_field("threadInitNumber", "I", &(n + 1), cap).unwrap();
+		let (m, cap) = thread.get_static_field::<i32>("threadInitNumber", "I", cap).unwrap();
+		assert!(m == n + 1);
+		let (_, cap) = thread.set_static_field("threadInitNumber", "I", &n, cap).unwrap();
+
+		let f = integer.get_static_field::<i32>("NO_SUCH_FIELD", "I", cap);
+		assert!(f.is_err());
+		let cap = env.exception_clear(f.err().unwrap());
+
+		// the method must be of the class or of one of its superclasses
+		let r = integer.call_static::<i64>(&millis, &[], cap);
+		assert!(r.is_err());
+		let (ex, exn) = JavaThrowable::occured(&env).err().unwrap();
+		let cap = JavaThrowable::clear(&env, exn);
+		let (iae, cap) = JavaClass::find(&env, "java/lang/IllegalArgumentException", cap).unwrap();
+		assert!(ex.is_instance_of(&iae, &cap));
+	}
+
+	// class RustJniTest {
+	// 	static native int add(int a, int b);
+	// 	native String hello(String name);
+	// }
+	static TEST_CLASS: &'static [u8] = &[
+		0xca, 0xfe, 0xba, 0xbe, 0x00, 0x00, 0x00, 0x34, 0x00, 0x0e, 0x0a, 0x00, 0x02, 0x00, 0x03, 0x07,
+		0x00, 0x04, 0x0c, 0x00, 0x05, 0x00, 0x06, 0x01, 0x00, 0x10, 0x6a, 0x61, 0x76, 0x61, 0x2f, 0x6c,
+		0x61, 0x6e, 0x67, 0x2f, 0x4f, 0x62, 0x6a, 0x65, 0x63, 0x74, 0x01, 0x00, 0x06, 0x3c, 0x69, 0x6e,
+		0x69, 0x74, 0x3e, 0x01, 0x00, 0x03, 0x28, 0x29, 0x56, 0x07, 0x00, 0x08, 0x01, 0x00, 0x0b, 0x52,
+		0x75, 0x73, 0x74, 0x4a, 0x6e, 0x69, 0x54, 0x65, 0x73, 0x74, 0x01, 0x00, 0x04, 0x43, 0x6f, 0x64,
+		0x65, 0x01, 0x00, 0x03, 0x61, 0x64, 0x64, 0x01, 0x00, 0x05, 0x28, 0x49, 0x49, 0x29, 0x49, 0x01,
+		0x00, 0x05, 0x68, 0x65, 0x6c, 0x6c, 0x6f, 0x01, 0x00, 0x26, 0x28, 0x4c, 0x6a, 0x61, 0x76, 0x61,
+		0x2f, 0x6c, 0x61, 0x6e, 0x67, 0x2f, 0x53, 0x74, 0x72, 0x69, 0x6e, 0x67, 0x3b, 0x29, 0x4c, 0x6a,
+		0x61, 0x76, 0x61, 0x2f, 0x6c, 0x61, 0x6e, 0x67, 0x2f, 0x53, 0x74, 0x72, 0x69, 0x6e, 0x67, 0x3b,
+		0x00, 0x20, 0x00, 0x07, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x05,
+		0x00, 0x06, 0x00, 0x01, 0x00, 0x09, 0x00, 0x00, 0x00, 0x11, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00,
+		0x00, 0x05, 0x2a, 0xb7, 0x00, 0x01, 0xb1, 0x00, 0x00, 0x00, 0x00, 0x01, 0x08, 0x00, 0x0a, 0x00,
+		0x0b, 0x00, 0x00, 0x01, 0x00, 0x00, 0x0c, 0x00, 0x0d, 0x00, 0x00, 0x00, 0x00,
+	];
+
+	extern "system" fn native_add(_env: *mut JNIEnvImpl, _cls: jobject, a: jint, b: jint) -> jint {
+		a + b
+	}
+
+	extern "system" fn native_hello(env: *mut JNIEnvImpl, this: jobject, name: jobject) -> jobject {
+		let env = unsafe { JavaEnv::from_raw(env) }.unwrap();
+		let cap = JavaThrowable::check(&env).unwrap();
+		assert!(env.jvm().version() == env.version(&cap));
+		let this = unsafe { JavaObject::from_borrowed(&env, this) }.unwrap();
+		let name = unsafe { JavaString::from_borrowed(&env, name) }.unwrap();
+		assert!(this.ref_type() == RefType::Borrowed);
+		assert!(name.ref_type() == RefType::Borrowed);
+		let res = format!("Hello, {}!", name.to_str(&cap).unwrap());
+		let (res, _) = JavaString::new(&env, &res, cap).unwrap();
+		res.into_raw()
+	}
+
+	fn test_JavaEnv_from_raw(jvm: &JavaVM) {
+		let (env, cap) = jvm.get_env().unwrap();
+		let (cls, cap) = JavaClass::find(&env, "RustJniTest", cap).unwrap();
+		let hello = native_hello as extern "system" fn(*mut JNIEnvImpl, jobject, jobject) -> jobject;
+		let native = NativeMethod::new("hello", "(Ljava/lang/String;)Ljava/lang/String;", hello).unwrap();
+		let cap = cls.register_natives(&[native], cap).unwrap();
+
+		let (obj, cap) = cls.new_object("()V", &[], cap).unwrap();
+		let (method, cap) = JavaMethod::new(&cls, "hello", "(Ljava/lang/String;)Ljava/lang/String;", cap).unwrap();
+		let (name, cap) = JavaString::new(&env, "Rust", cap).unwrap();
+		let (res, cap) = obj.call::<Option<JavaString>>(&method, &[JValue::from(&name)], cap).unwrap();
+		assert!(res.unwrap().to_str(&cap).unwrap() == "Hello, Rust!");
+		assert!(name.to_str(&cap).unwrap() == "Rust");
+		assert!(cls.unregister_natives(&cap) == JniError::JNI_OK);
+	}
+
+	fn test_catch_panic(jvm: &JavaVM) {
+		// the application's panic hook still runs
+		static HOOK_CALLS: ::std::sync::atomic::AtomicUsize = ::std::sync::atomic::AtomicUsize::new(0);
+		let default_hook = panic::take_hook();
+		panic::set_hook(Box::new(move |info| {
+			HOOK_CALLS.fetch_add(1, ::std::sync::atomic::Ordering::SeqCst);
+			default_hook(info);
+		}));
+
+		let (env, _) = jvm.get_env().unwrap();
+		let r = JavaThrowable::catch_panic(&env, "java/lang/IllegalStateException", || 42i32);
+		assert!(r == 42);
+
+		let r = JavaThrowable::catch_panic(&env, "java/lang/IllegalStateException", || -> i32 { panic!("boom") });
+		assert!(r == 0);
+		assert!(HOOK_CALLS.load(::std::sync::atomic::Ordering::SeqCst) == 1);
+		let (ex, exn) = JavaThrowable::occured(&env).err().unwrap();
+		let cap = JavaThrowable::clear(&env, exn);
+		let (cls, cap) = JavaClass::find(&env, "java/lang/IllegalStateException", cap).unwrap();
+		assert!(ex.is_instance_of(&cls, &cap));
+		let (get_message, cap) = JavaMethod::new(&cls, "getMessage", "()Ljava/lang/String;", cap).unwrap();
+		let (msg, cap) = ex.call::<Option<JavaString>>(&get_message, &[], cap).unwrap();
+		let msg = msg.unwrap().to_str(&cap).unwrap();
+		assert!(msg.starts_with("Rust panic at "));
+		assert!(msg.contains("jni.rs"));
+		assert!(msg.ends_with(": boom"));
+	}
+
+	fn test_ToJavaException(jvm: &JavaVM) {
+		let (env, cap) = jvm.get_env().unwrap();
+		let (iae, cap) = JavaClass::find(&env, "java/lang/IllegalArgumentException", cap).unwrap();
+		let (ioe, cap) = JavaClass::find(&env, "java/io/IOException", cap).unwrap();
+		let (rte, cap) = JavaClass::find(&env, "java/lang/RuntimeException", cap).unwrap();
+		let (throwable, cap) = JavaClass::find(&env, "java/lang/Throwable", cap).unwrap();
+		let (get_message, cap) = JavaMethod::new(&throwable, "getMessage", "()Ljava/lang/String;", cap).unwrap();
+		let (get_cause, cap) = JavaMethod::new(&throwable, "getCause", "()Ljava/lang/Throwable;", cap).unwrap();
+
+		let _ = JavaThrowable::throw_error(&env, &IllegalArgument("bad".to_string()), cap);
+		let (ex, exn) = JavaThrowable::occured(&env).err().unwrap();
+		let cap = JavaThrowable::clear(&env, exn);
+		assert!(ex.is_instance_of(&iae, &cap));
+		let (msg, cap) = ex.call::<Option<JavaString>>(&get_message, &[], cap).unwrap();
+		assert!(msg.unwrap().to_str(&cap).unwrap() == "bad");
+
+		let err = io::Error::new(io::ErrorKind::Other, IllegalArgument("worse".to_string()));
+		let r = JavaThrowable::catch_panic(&env, "java/lang/IllegalStateException", || -> Result<i32, io::Error> { Err(err) });
+		assert!(r == 0);
+		let (ex, exn) = JavaThrowable::occured(&env).err().unwrap();
+		let cap = JavaThrowable::clear(&env, exn);
+		assert!(ex.is_instance_of(&ioe, &cap));
+		let (cause, cap) = ex.call::<Option<JavaThrowable>>(&get_cause, &[], cap).unwrap();
+		let cause = cause.unwrap();
+		assert!(cause.is_instance_of(&rte, &cap));
+		let (msg, cap) = cause.call::<Option<JavaString>>(&get_message, &[], cap).unwrap();
+		assert!(msg.unwrap().to_str(&cap).unwrap() == "worse");
+		let (cause, _) = cause.call::<Option<JavaThrowable>>(&get_cause, &[], cap).unwrap();
+		assert!(cause.is_none());
+	}
+
+	fn test_JavaException(jvm: &JavaVM) {
+		let (env, cap) = jvm.get_env().unwrap();
+		let (integer, cap) = JavaClass::find(&env, "java/lang/Integer", cap).unwrap();
+		let (parse_int, cap) = JavaMethod::new_static(&integer, "parseInt", "(Ljava/lang/String;)I", cap).unwrap();
+		let (s, cap) = JavaString::new(&env, "x", cap).unwrap();
+		let exn = integer.call_static::<i32>(&parse_int, &[JValue::from(&s)], cap).err().unwrap();
+		let (ex, cap) = JavaException::take(&env, exn).unwrap();
+		assert!(ex.class_name == "java.lang.NumberFormatException");
+		assert!(ex.message == Some("For input string: \"x\"".to_string()));
+		assert!(format!("{}", ex) == "java.lang.NumberFormatException: For input string: \"x\"");
+		assert!(ex.stack_trace.iter().any(|f| f.class_name == "java.lang.Integer" && f.method_name == "parseInt"));
+		assert!(format!("{:#}", ex).contains("\n\tat java.lang.Integer.parseInt("));
+		assert!(ex.cause.is_none());
+
+		let err = io::Error::new(io::ErrorKind::Other, IllegalArgument("bad".to_string()));
+		let exn = JavaThrowable::throw_error(&env, &err, cap);
+		let (ex, _) = JavaException::take(&env, exn).unwrap();
+		assert!(ex.class_name == "java.io.IOException");
+		let cause = ex.source().unwrap();
+		assert!(cause.to_string() == "java.lang.RuntimeException: bad");
+		assert!(cause.source().is_none());
+		assert!(format!("{:#}", ex).ends_with("\nCaused by: java.lang.RuntimeException: bad"));
+	}
+
+	fn test_JCatch(jvm: &JavaVM) {
+		let (env, cap) = jvm.get_env().unwrap();
+		let (integer, cap) = JavaClass::find(&env, "java/lang/Integer", cap).unwrap();
+		let (iae, cap) = JavaClass::find(&env, "java/lang/IllegalArgumentException", cap).unwrap();
+		let (parse_int, cap) = JavaMethod::new_static(&integer, "parseInt", "(Ljava/lang/String;)I", cap).unwrap();
+		let (good, cap) = JavaString::new(&env, "42", cap).unwrap();
+		let (bad, cap) = JavaString::new(&env, "x", cap).unwrap();
+
+		let (r, cap) = integer.call_static::<i32>(&parse_int, &[JValue::from(&good)], cap).catch(&env, &iae).unwrap();
+		assert!(r.ok() == Some(42));
+
+		let r = integer.call_static::<i32>(&parse_int, &[JValue::from(&bad)], cap);
+		let r = r.catch(&env, "java/io/IOException");
+		assert!(r.is_err());
+		let r: JniResult<i32> = Err(r.err().unwrap());
+		let r = r.catch(&env, "no/such/Exception");
+		assert!(r.is_err());
+		assert!(JavaThrowable::check(&env).is_err());
+		let r: JniResult<i32> = Err(r.err().unwrap());
+		let (r, cap) = r.catch(&env, "java/lang/IllegalArgumentException").unwrap();
+		assert!(r.err().unwrap().is_instance_of(&iae, &cap));
+
+		let exn = integer.call_static::<i32>(&parse_int, &[JValue::from(&bad)], cap).err().unwrap();
+		let (ex, cap) = JavaThrowable::catch(&env, &iae, exn).unwrap();
+		assert!(ex.is_instance_of(&iae, &cap));
+	}
+
+	fn test_GlobalRef(jvm: &JavaVM) {
+		let (env, cap) = jvm.get_env().unwrap();
+		let (cls, cap) = JavaClass::find(&env, "java/lang/String", cap).unwrap();
+		let (global, cap): (GlobalRef<JavaClass<'static>>, _) = GlobalRef::new(&cls, cap).unwrap();
+		assert!(global.as_local(&env) == cls);
+
+		// dropped with an exception pending
+		let (other, cap): (GlobalRef<JavaClass<'static>>, _) = GlobalRef::new(&cls, cap).unwrap();
+		let exn = JavaClass::find(&env, "no/such/Class", cap).err().unwrap();
+		drop(other);
+		let _ = JavaThrowable::clear(&env, exn);
+
+		let global = ::std::thread::spawn(move || {
+			{
+				let (env, cap) = global.jvm().get_env().unwrap();
+				let (cls, _) = JavaClass::find(&env, "java/lang/String", cap).unwrap();
+				let local = global.as_local(&env);
+				assert!(local == cls);
+				assert!(local.ref_type() == RefType::Borrowed);
+			}
+			global
+		}).join().unwrap();
+		assert!(global.as_local(&env) == cls);
+
+		// dropped in a thread which is not attached
+		::std::thread::spawn(move || {
+			drop(global);
+		}).join().unwrap();
+	}
+
+	fn test_WeakRef(jvm: &JavaVM) {
+		let (env, cap) = jvm.get_env().unwrap();
+		let (object, cap) = JavaClass::find(&env, "java/lang/Object", cap).unwrap();
+		let (system, cap) = JavaClass::find(&env, "java/lang/System", cap).unwrap();
+		let (gc, cap) = JavaMethod::new_static(&system, "gc", "()V", cap).unwrap();
+		let (obj, cap) = object.new_object("()V", &[], cap).unwrap();
+		let (weak, cap): (WeakRef<JavaObject<'static>>, _) = obj.weak(cap).unwrap();
+
+		{
+			let strong = weak.upgrade(&env, &cap).unwrap();
+			assert!(strong == obj);
+			assert!(strong.ref_type() == RefType::Local);
+			let global = weak.upgrade_global(&env, &cap).unwrap();
+			assert!(global.as_local(&env) == obj);
+		}
+
+		drop(obj);
+		let mut cap = cap;
+		for _ in 0..10 {
+			let (_, c) = system.call_static::<()>(&gc, &[], cap).unwrap();
+			cap = c;
+			if weak.upgrade(&env, &cap).is_none() {
+				break;
+			}
+		}
+		assert!(weak.upgrade(&env, &cap).is_none());
+		assert!(weak.upgrade_global(&env, &cap).is_none());
+
+		// dropped with an exception pending
+		let exn = JavaClass::find(&env, "no/such/Class", cap).err().unwrap();
+		drop(weak);
+		let _ = JavaThrowable::clear(&env, exn);
+	}
+
+	fn test_query_ref_type(jvm: &JavaVM) {
+		let (env, cap) = jvm.get_env().unwrap();
+		let (cls, cap) = JavaClass::find(&env, "java/lang/Object", cap).unwrap();
+		assert!(cls.query_ref_type(&cap) == Some(RefType::Local));
+		let (global, cap) = cls.global(cap).unwrap();
+		assert!(global.query_ref_type(&cap) == Some(RefType::Global));
+		let (local, cap) = global.local(cap).unwrap();
+		assert!(local.query_ref_type(&cap) == Some(RefType::Local));
+		let borrowed: JavaClass = unsafe { JObject::from_borrowed(&env, local.get_obj()) }.unwrap();
+		assert!(borrowed.query_ref_type(&cap) == Some(RefType::Local));
+	}
+
+	fn test_with_local_frame(jvm: &JavaVM) {
+		let (env, cap) = jvm.get_env().unwrap();
+		let (res, cap) = env.with_local_frame::<JavaString<'static>, _>(4, cap, |env, mut cap| {
+			let mut last = None;
+			for i in 0..10000 {
+				let (s, c) = try!(JavaString::new(env, &i.to_string(), cap));
+				last = Some(s);
+				cap = c;
+			}
+			Ok((last, cap))
+		}).unwrap();
+		let res = res.unwrap();
+		assert!(res.to_str(&cap).unwrap() == "9999");
+		assert!(res.query_ref_type(&cap) == Some(RefType::Local));
+
+		// the outer locals stay alive, and can be used in the frames
+		let (suffix, mut cap) = JavaString::new(&env, "n", cap).unwrap();
+		let (string, c) = JavaClass::find(&env, "java/lang/String", cap).unwrap();
+		let (concat, c) = JavaMethod::new(&string, "concat", "(Ljava/lang/String;)Ljava/lang/String;", c).unwrap();
+		cap = c;
+		let mut all = vec![];
+		for i in 0..100 {
+			let (s, c) = env.with_local_frame::<JavaString<'static>, _>(4, cap, |env, cap| {
+				let (n, cap) = try!(JavaString::new(env, &i.to_string(), cap));
+				n.call::<Option<JavaString>>(&concat, &[JValue::from(&suffix)], cap)
+			}).unwrap();
+			all.push(s.unwrap());
+			cap = c;
+		}
+		assert!(res.to_str(&cap).unwrap() == "9999");
+		assert!(all[42].to_str(&cap).unwrap() == "42n");
+
+		let (res, cap) = env.with_local_frame::<JavaString<'static>, _>(4, cap, |_, cap| Ok((None, cap))).unwrap();
+		assert!(res.is_none());
+
+		let res = env.with_local_frame::<JavaClass<'static>, _>(4, cap, |env, cap| {
+			let (cls, cap) = try!(JavaClass::find(env, "no/such/Class", cap));
+			Ok((Some(cls), cap))
+		});
+		let exn = res.err().unwrap();
+		let cap = env.exception_clear(exn);
+
+		let res = panic::catch_unwind(AssertUnwindSafe(|| {
+			let _ = env.with_local_frame::<JavaObject<'static>, _>(4, cap, |_, _| panic!("boom"));
+		}));
+		assert!(res.is_err());
+
+		// a local of the outer env would be deleted with the frame
+		let cap = env.exception_check().unwrap();
+		let res = panic::catch_unwind(AssertUnwindSafe(|| {
+			let _ = env.with_local_frame::<JavaObject<'static>, _>(4, cap, |_, cap| {
+				let (_s, cap) = try!(JavaString::new(&env, "outer", cap));
+				Ok((None, cap))
+			});
+		}));
+		assert!(res.is_err());
+		let (s, cap) = JavaString::new(&env, "after", env.exception_check().unwrap()).unwrap();
+		assert!(s.to_str(&cap).unwrap() == "after");
+	}
+
+	#[cfg(feature = "track-refs")]
+	fn test_ref_counts(jvm: &JavaVM) {
+		let (env, cap) = jvm.get_env().unwrap();
+		assert!(env.ref_counts(RefType::Local).live() == 0);
+		let globals = env.ref_counts(RefType::Global).live();
+		{
+			let (s, cap) = JavaString::new(&env, "a", cap).unwrap();
+			let (g, cap) = s.global(cap).unwrap();
+			let (_w, _cap) = s.weak::<JavaString<'static>>(cap).unwrap();
+			assert!(env.ref_counts(RefType::Local).live() == 1);
+			assert!(env.ref_counts(RefType::Global).live() == globals + 1);
+			assert!(env.ref_counts(RefType::Weak).live() >= 1);
+			drop(g);
+			assert!(env.ref_counts(RefType::Global).live() == globals);
+		}
+		let counts = env.ref_counts(RefType::Local);
+		assert!(counts.live() == 0);
+		assert!(counts.created == 1 && counts.high_water == 1);
+	}
+
+	fn test_get_env_cached(jvm: &JavaVM) {
+		let ptr = {
+			let (env, _cap) = jvm.get_env_cached().unwrap();
+			assert!(!env.detach);
+			env.ptr
+		};
+		// still attached, so this one doesn't attach again
+		let (env, _cap) = jvm.get_env().unwrap();
+		assert!(!env.detach);
+		assert!(env.ptr == ptr);
+	}
+
+	fn test_attach(jvm: &JavaVM) {
+		let group = {
+			let (env, cap) = jvm.get_env().unwrap();
+			let (cls, cap) = JavaClass::find(&env, "java/lang/ThreadGroup", cap).unwrap();
+			let (name, cap) = JavaString::new(&env, "rust-group", cap).unwrap();
+			let (group, cap) = cls.new_object("(Ljava/lang/String;)V", &[JValue::from(&name)], cap).unwrap();
+			GlobalRef::<JavaObject<'static>>::new(&group, cap).unwrap().0
+		};
+
+		// a new thread, which is not attached yet
+		::std::thread::scope(|s| s.spawn(|| {
+			let args = JavaVMAttachArgs::new(jvm.version(), "rust-worker", Some(&group));
+			let (env, cap) = jvm.attach(args).unwrap();
+			assert!(env.detach);
+
+			let (thread, cap) = JavaClass::find(&env, "java/lang/Thread", cap).unwrap();
+			let (current, cap) = JavaMethod::new_static(&thread, "currentThread", "()Ljava/lang/Thread;", cap).unwrap();
+			let (t, cap) = thread.call_static::<Option<JavaObject>>(&current, &[], cap).unwrap();
+			let t = t.unwrap();
+			let (get_name, cap) = JavaMethod::new(&thread, "getName", "()Ljava/lang/String;", cap).unwrap();
+			let (name, cap) = t.call::<Option<JavaString>>(&get_name, &[], cap).unwrap();
+			assert!(name.unwrap().to_str(&cap).unwrap() == "rust-worker");
+			let (get_group, cap) = JavaMethod::new(&thread, "getThreadGroup", "()Ljava/lang/ThreadGroup;", cap).unwrap();
+			let (g, _) = t.call::<Option<JavaObject>>(&get_group, &[], cap).unwrap();
+			assert!(g.unwrap() == group.as_local(&env));
+		}).join().unwrap());
+	}
+
+	fn test_register_natives(jvm: &JavaVM) {
+		let (env, cap) = jvm.get_env().unwrap();
+		let (loader_cls, cap) = JavaClass::find(&env, "java/lang/ClassLoader", cap).unwrap();
+		let (get_loader, cap) = JavaMethod::new_static(&loader_cls, "getSystemClassLoader", "()Ljava/lang/ClassLoader;", cap).unwrap();
+		let (loader, cap) = loader_cls.call_static::<Option<JavaObject>>(&get_loader, &[], cap).unwrap();
+		let (cls, cap) = JavaClass::define(&env, "RustJniTest", &loader.unwrap(), TEST_CLASS, cap).unwrap();
+
+		let add = native_add as extern "system" fn(*mut JNIEnvImpl, jobject, jint, jint) -> jint;
+		assert!(NativeMethod::new("add", "(II)J", add).is_none());
+		assert!(NativeMethod::new("add", "(IJ)I", add).is_none());
+		assert!(NativeMethod::new("add", "(I)I", add).is_none());
+		assert!(NativeMethod::new("add", "(II", add).is_none());
+		let native = NativeMethod::new("add", "(II)I", add).unwrap();
+		assert!(native.name() == "add");
+
+		let cap = cls.register_natives(&[native], cap).unwrap();
+		let (method, cap) = JavaMethod::new_static(&cls, "add", "(II)I", cap).unwrap();
+		let (r, cap) = cls.call_static::<i32>(&method, &[JValue::from(2), JValue::from(3)], cap).unwrap();
+		assert!(r == 5);
+
+		assert!(cls.unregister_natives(&cap) == JniError::JNI_OK);
+		let r = cls.call_static::<i32>(&method, &[JValue::from(2), JValue::from(3)], cap);
+		assert!(r.is_err());
+		let cap = env.exception_clear(r.err().unwrap());
+
+		let native = NativeMethod::new("sub", "(II)I", add).unwrap();
+		let r = cls.register_natives(&[native], cap);
+		assert!(r.is_err());
+		let _ = env.exception_clear(r.err().unwrap().1);
+	}
 
+	#[test]
+	fn test_JavaVM() {
 		let jvm = JavaVM::new(
 			JavaVMInitArgs::new(
 				JniVersion::JNI_VERSION_1_8,
@@ -1922,21 +4944,67 @@ mod tests {
 
 		test_JavaEnv(&jvm);
 		test_JavaEnv(&jvm);
+		test_JavaMethod(&jvm);
+		test_JavaMethod_nonvirtual(&jvm);
+		test_JavaClass_static(&jvm);
+		test_JavaField(&jvm);
+		test_JavaClass_new_object(&jvm);
+		test_register_natives(&jvm);
+		test_JavaEnv_from_raw(&jvm);
+		test_catch_panic(&jvm);
+		test_ToJavaException(&jvm);
+		test_JavaException(&jvm);
+		test_JCatch(&jvm);
+		test_GlobalRef(&jvm);
+		test_WeakRef(&jvm);
+		test_query_ref_type(&jvm);
+		test_with_local_frame(&jvm);
+		test_attach(&jvm);
+		#[cfg(feature = "track-refs")]
+		test_ref_counts(&jvm);
+
+		assert!(JavaVM::current().unwrap() == jvm);
+		// the version is asked for the same way on any JVM, even from a detached thread
+		let version = thread::spawn(|| JavaVM::current().unwrap().version()).join().unwrap();
+		{
+			let (env, cap) = jvm.get_env().unwrap();
+			assert!(version == env.version(&cap));
+			assert!(version >= jvm.version());
+		}
+		// only the owner can destroy it
+		assert!(JavaVM::current().unwrap().destroy() == Err(JniError::JNI_EINVAL));
 
-		let t1 = thread::scoped(|| {
-			test_JavaEnv(&jvm);
+		let jvm = jvm.shared();
+
+		let jvm1 = jvm.clone();
+		let t1 = thread::spawn(move || {
+			test_JavaEnv(&jvm1);
+		});
+
+		let jvm2 = jvm.clone();
+		let t2 = thread::spawn(move || {
+			test_JavaEnv(&jvm2);
 		});
 
-		let t2 = thread::scoped(|| {
-			test_JavaEnv(&jvm);
+		let jvm3 = jvm.clone();
+		let t3 = thread::spawn(move || {
+			test_JavaEnv(&jvm3);
 		});
 
-		let t3 = thread::scoped(|| {
-			test_JavaEnv(&jvm);
+		let jvm4 = jvm.clone();
+		let t4 = thread::spawn(move || {
+			test_get_env_cached(&jvm4);
+			test_JavaEnv(&jvm4);
+			assert!(JavaVM::current().unwrap() == *jvm4);
 		});
 
-		let _ = t1.join();
-		let _ = t2.join();
-		let _ = t3.join();
+		t1.join().unwrap();
+		t2.join().unwrap();
+		t3.join().unwrap();
+		t4.join().unwrap();
+
+		let jvm = jvm.try_unwrap().unwrap();
+		assert!(jvm.destroy() == Ok(()));
+		assert!(JavaVM::current().is_none());
 	}
 }
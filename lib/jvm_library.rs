@@ -0,0 +1,292 @@
+//! Loading of `libjvm` at runtime, with the `dynamic-jvm` feature.
+//!
+//! Without the feature, the JNI invocation functions are linked at build
+//! time, so a binary does not start if `libjvm` is not found.  With it,
+//! `libjvm` is opened with `dlopen` the first time a JVM is created or
+//! looked up, and a missing library is a `CreateError::Load` from
+//! `JavaVM::new`.  Use `JvmLibrary::load` to choose the library.
+//!
+//! This is only done on Unix, elsewhere the feature does nothing.
+
+use ::std::env;
+use ::std::error::Error;
+use ::std::ffi::{CStr, CString};
+use ::std::fmt;
+use ::std::fs;
+use ::std::mem;
+use ::std::path::{Path, PathBuf};
+use ::std::sync::{Mutex, MutexGuard};
+
+use super::native::*;
+
+#[cfg_attr(target_os = "linux", link(name = "dl"))]
+extern "C" {
+	fn dlopen(filename: *const ::libc::c_char, flag: ::libc::c_int) -> *mut ::libc::c_void;
+	fn dlsym(handle: *mut ::libc::c_void, symbol: *const ::libc::c_char) -> *mut ::libc::c_void;
+	fn dlerror() -> *const ::libc::c_char;
+}
+
+const RTLD_NOW: ::libc::c_int = 2;
+#[cfg(target_os = "macos")]
+const RTLD_NOLOAD: ::libc::c_int = 0x10;
+#[cfg(not(target_os = "macos"))]
+const RTLD_NOLOAD: ::libc::c_int = 4;
+
+#[cfg(target_os = "macos")]
+const LIBJVM: &'static str = "libjvm.dylib";
+#[cfg(not(target_os = "macos"))]
+const LIBJVM: &'static str = "libjvm.so";
+
+/// The architecture directory of Java 8 JREs.
+#[cfg(target_arch = "x86_64")]
+const ARCH: &'static str = "amd64";
+#[cfg(target_arch = "x86")]
+const ARCH: &'static str = "i386";
+#[cfg(not(any(target_arch = "x86_64", target_arch = "x86")))]
+const ARCH: &'static str = ::std::env::consts::ARCH;
+
+/// Directories with JDKs and JREs on common systems.
+const JAVA_ROOTS: &'static [&'static str] = &[
+	"/usr/lib/jvm",
+	"/usr/java",
+	"/opt/java",
+	"/Library/Java/JavaVirtualMachines",
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LoadError {
+	/// `libjvm` is not in any of these paths.
+	NotFound(Vec<PathBuf>),
+	/// `JvmLibrary::from_java_home` was called without `JAVA_HOME` set.
+	JavaHomeUnset,
+	/// `dlopen` failed, with the message from `dlerror`.
+	Open(PathBuf, String),
+	/// The library does not export a JNI invocation function.
+	MissingSymbol(PathBuf, &'static str),
+}
+
+impl fmt::Display for LoadError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			LoadError::NotFound(ref paths) => {
+				try!(write!(f, "{} not found, tried:", LIBJVM));
+				for path in paths {
+					try!(write!(f, " {}", path.display()));
+				}
+				Ok(())
+			},
+			LoadError::JavaHomeUnset => write!(f, "JAVA_HOME is not set"),
+			LoadError::Open(ref path, ref msg) => write!(f, "can't load {}: {}", path.display(), msg),
+			LoadError::MissingSymbol(ref path, sym) => write!(f, "{} does not export {}", path.display(), sym),
+		}
+	}
+}
+
+impl Error for LoadError {}
+
+/// A loaded `libjvm` with its JNI invocation functions.
+///
+/// There can only be one JVM in a process, so the first library loaded
+/// is used for all JVMs, and is never unloaded.
+#[derive(Debug)]
+pub struct JvmLibrary {
+	path: PathBuf,
+	create_java_vm: extern "C" fn(vm: *mut *mut JavaVMImpl, env: *mut *mut JNIEnvImpl, args: *mut JavaVMInitArgsImpl) -> JniError,
+	get_default_java_vm_init_args: extern "C" fn(args: *mut JavaVMInitArgsImpl) -> JniError,
+	get_created_java_vms: extern "C" fn(vm: *mut *mut JavaVMImpl, bufLen: jsize, nVMs: *mut jsize) -> JniError,
+}
+
+static LIBRARY: Mutex<Option<&'static JvmLibrary>> = Mutex::new(None);
+/// Why `find` failed, so that it does not search again every time.
+static FIND_ERROR: Mutex<Option<LoadError>> = Mutex::new(None);
+
+// nothing is left half-done while the locks are held
+fn lock<'a, T>(mutex: &'a Mutex<T>) -> MutexGuard<'a, T> {
+	match mutex.lock() {
+		Ok(guard) => guard,
+		Err(err) => err.into_inner(),
+	}
+}
+
+impl JvmLibrary {
+	/// Loads `libjvm` from the given path, unless one is already loaded.
+	pub fn load(path: &Path) -> Result<&'static JvmLibrary, LoadError> {
+		JvmLibrary::install(|| JvmLibrary::open(path, RTLD_NOW))
+	}
+
+	/// Loads `libjvm` from the JDK or JRE in `JAVA_HOME`, unless one is already loaded.
+	pub fn from_java_home() -> Result<&'static JvmLibrary, LoadError> {
+		JvmLibrary::install(|| {
+			let home = match env::var_os("JAVA_HOME") {
+				Some(home) => PathBuf::from(home),
+				None => return Err(LoadError::JavaHomeUnset),
+			};
+			JvmLibrary::open_first(&candidates(&home))
+		})
+	}
+
+	/// Finds `libjvm`: the one already loaded in the process, like when
+	/// the program was started by `java`, the one in `JAVA_HOME`, or the
+	/// first one in the usual JDK directories.  If none is found, the same
+	/// error is returned from then on, unless one is loaded in another way.
+	pub fn find() -> Result<&'static JvmLibrary, LoadError> {
+		let mut error = lock(&FIND_ERROR);
+		let res = JvmLibrary::install(|| {
+			if let Some(ref err) = *error {
+				return Err(err.clone());
+			}
+			if let Ok(lib) = JvmLibrary::open(Path::new(LIBJVM), RTLD_NOW | RTLD_NOLOAD) {
+				return Ok(lib);
+			}
+			let mut paths = vec![];
+			if let Some(home) = env::var_os("JAVA_HOME") {
+				paths.extend(candidates(Path::new(&home)));
+			}
+			for root in JAVA_ROOTS {
+				let mut homes: Vec<PathBuf> = match fs::read_dir(root) {
+					Ok(dir) => dir.filter_map(|e| e.ok()).map(|e| e.path()).collect(),
+					Err(_) => continue,
+				};
+				homes.sort();
+				for home in homes {
+					paths.extend(candidates(&home));
+					// macOS bundles
+					paths.extend(candidates(&home.join("Contents/Home")));
+				}
+			}
+			JvmLibrary::open_first(&paths)
+		});
+		if let Err(ref err) = res {
+			*error = Some(err.clone());
+		}
+		res
+	}
+
+	/// The path the library was loaded from.
+	pub fn path(&self) -> &Path {
+		&self.path
+	}
+
+	fn install<F>(open: F) -> Result<&'static JvmLibrary, LoadError> where F: FnOnce() -> Result<JvmLibrary, LoadError> {
+		let mut lib = lock(&LIBRARY);
+		if let Some(lib) = *lib {
+			return Ok(lib);
+		}
+		let res: &'static JvmLibrary = Box::leak(Box::new(try!(open())));
+		*lib = Some(res);
+		Ok(res)
+	}
+
+	fn open_first(paths: &[PathBuf]) -> Result<JvmLibrary, LoadError> {
+		for path in paths {
+			if path.is_file() {
+				return JvmLibrary::open(path, RTLD_NOW);
+			}
+		}
+		Err(LoadError::NotFound(paths.to_vec()))
+	}
+
+	fn open(path: &Path, flags: ::libc::c_int) -> Result<JvmLibrary, LoadError> {
+		let name = match CString::new(path.to_string_lossy().into_owned()) {
+			Ok(name) => name,
+			Err(_) => return Err(LoadError::Open(path.to_path_buf(), "invalid path".to_string())),
+		};
+		let handle = unsafe { dlopen(name.as_ptr(), flags) };
+		if handle.is_null() {
+			let msg = unsafe {
+				let err = dlerror();
+				if err.is_null() {
+					"unknown error".to_string()
+				} else {
+					CStr::from_ptr(err).to_string_lossy().into_owned()
+				}
+			};
+			return Err(LoadError::Open(path.to_path_buf(), msg));
+		}
+
+		let sym = |name: &'static str| {
+			let cname = CString::new(name).unwrap();
+			let ptr = unsafe { dlsym(handle, cname.as_ptr()) };
+			if ptr.is_null() {
+				Err(LoadError::MissingSymbol(path.to_path_buf(), name))
+			} else {
+				Ok(ptr)
+			}
+		};
+		// the library is never closed, as a JVM can't be unloaded
+		unsafe {
+			Ok(JvmLibrary{
+				path: path.to_path_buf(),
+				create_java_vm: mem::transmute(try!(sym("JNI_CreateJavaVM"))),
+				get_default_java_vm_init_args: mem::transmute(try!(sym("JNI_GetDefaultJavaVMInitArgs"))),
+				get_created_java_vms: mem::transmute(try!(sym("JNI_GetCreatedJavaVMs"))),
+			})
+		}
+	}
+}
+
+/// The places of `libjvm` in a JDK or a JRE.
+fn candidates(home: &Path) -> Vec<PathBuf> {
+	vec![
+		home.join("lib/server").join(LIBJVM),
+		home.join("jre/lib/server").join(LIBJVM),
+		home.join("lib").join(ARCH).join("server").join(LIBJVM),
+		home.join("jre/lib").join(ARCH).join("server").join(LIBJVM),
+	]
+}
+
+// These replace the functions linked from `libjvm` without the feature.
+
+pub unsafe fn JNI_CreateJavaVM(vm: *mut *mut JavaVMImpl, env: *mut *mut JNIEnvImpl, args: *mut JavaVMInitArgsImpl) -> JniError {
+	match JvmLibrary::find() {
+		Ok(lib) => (lib.create_java_vm)(vm, env, args),
+		Err(_) => JniError::JNI_ERR,
+	}
+}
+
+pub unsafe fn JNI_GetDefaultJavaVMInitArgs(args: *mut JavaVMInitArgsImpl) -> JniError {
+	match JvmLibrary::find() {
+		Ok(lib) => (lib.get_default_java_vm_init_args)(args),
+		Err(_) => JniError::JNI_ERR,
+	}
+}
+
+pub unsafe fn JNI_GetCreatedJavaVMs(vm: *mut *mut JavaVMImpl, bufLen: jsize, nVMs: *mut jsize) -> JniError {
+	match JvmLibrary::find() {
+		Ok(lib) => (lib.get_created_java_vms)(vm, bufLen, nVMs),
+		Err(_) => JniError::JNI_ERR,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_candidates() {
+		let paths = candidates(Path::new("/jdk"));
+		assert!(paths[0] == Path::new("/jdk/lib/server").join(LIBJVM));
+		assert!(paths.iter().all(|p| p.starts_with("/jdk")));
+	}
+
+	#[test]
+	fn test_open_error() {
+		match JvmLibrary::open(Path::new("/no/such/libjvm.so"), RTLD_NOW) {
+			Err(LoadError::Open(path, _)) => assert!(path == Path::new("/no/such/libjvm.so")),
+			_ => panic!("expected LoadError::Open"),
+		}
+		let err = JvmLibrary::open_first(&[PathBuf::from("/no/such/libjvm.so")]).err().unwrap();
+		assert!(err == LoadError::NotFound(vec![PathBuf::from("/no/such/libjvm.so")]));
+	}
+
+	#[test]
+	fn test_find() {
+		let lib = JvmLibrary::find().unwrap();
+		assert!(lib.path().ends_with(LIBJVM));
+		let mut count: jsize = -1;
+		assert!((lib.get_created_java_vms)(0 as *mut *mut JavaVMImpl, 0, &mut count) == JniError::JNI_OK);
+		assert!(count >= 0);
+		// the library is only loaded once
+		assert!(JvmLibrary::find().unwrap() as *const JvmLibrary == lib as *const JvmLibrary);
+	}
+}
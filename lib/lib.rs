@@ -4,15 +4,23 @@
 #![allow(non_camel_case_types)]
 #![allow(non_snake_case)]
 #![forbid(improper_ctypes)]
-#![feature(scoped)]
 
 extern crate libc;
+extern crate jni_macros;
 
 pub use self::jni::*;
 pub use self::j_chars::*;
+pub use self::ref_tracker::RefCounts;
+#[cfg(all(unix, feature = "dynamic-jvm"))]
+pub use self::jvm_library::{JvmLibrary, LoadError};
+pub use jni_macros::jni_export;
 
 pub use self::native::{JniVersion/*, JNI_VERSION_1_1, JNI_VERSION_1_2, JNI_VERSION_1_4, JNI_VERSION_1_6*/};
 
 pub mod native;
+pub mod signature;
 mod jni;
 mod j_chars;
+mod ref_tracker;
+#[cfg(all(unix, feature = "dynamic-jvm"))]
+mod jvm_library;
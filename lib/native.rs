@@ -29,18 +29,18 @@ pub type jobjectArray = jobject;
 pub type jweak = jobject;
 
 
-// TODO: deal with repr
 #[derive(Copy, Clone)]
-pub enum jvalue {
-	Jz(jboolean),
-	Jb(jbyte),
-	Jc(jchar),
-	Js(jshort),
-	Ji(jint),
-	Jj(jlong),
-	Jf(jfloat),
-	Jd(jdouble),
-	Jl(jobject),
+#[repr(C)]
+pub union jvalue {
+	pub z: jboolean,
+	pub b: jbyte,
+	pub c: jchar,
+	pub s: jshort,
+	pub i: jint,
+	pub j: jlong,
+	pub f: jfloat,
+	pub d: jdouble,
+	pub l: jobject,
 }
 
 
@@ -64,10 +64,38 @@ pub enum JniVersion {
 	JNI_VERSION_1_6 = 0x00010006,
 	JNI_VERSION_1_7 = 0x00010007,
 	JNI_VERSION_1_8 = 0x00010008,
+	JNI_VERSION_9 = 0x00090000,
+	JNI_VERSION_10 = 0x000a0000,
+	JNI_VERSION_19 = 0x00130000,
+	JNI_VERSION_20 = 0x00140000,
+	JNI_VERSION_21 = 0x00150000,
 }
 
 pub const MIN_JNI_VERSION: u32 = JniVersion::JNI_VERSION_1_1 as u32;
-pub const MAX_JNI_VERSION: u32 = JniVersion::JNI_VERSION_1_8 as u32;
+pub const MAX_JNI_VERSION: u32 = JniVersion::JNI_VERSION_21 as u32;
+
+const JNI_VERSIONS: [JniVersion; 11] = [
+	JniVersion::JNI_VERSION_1_1,
+	JniVersion::JNI_VERSION_1_2,
+	JniVersion::JNI_VERSION_1_4,
+	JniVersion::JNI_VERSION_1_6,
+	JniVersion::JNI_VERSION_1_7,
+	JniVersion::JNI_VERSION_1_8,
+	JniVersion::JNI_VERSION_9,
+	JniVersion::JNI_VERSION_10,
+	JniVersion::JNI_VERSION_19,
+	JniVersion::JNI_VERSION_20,
+	JniVersion::JNI_VERSION_21,
+];
+
+impl JniVersion {
+	/// Maps a version returned by `GetVersion` to the newest known version
+	/// not above it, since newer JVMs support all the older versions.
+	/// Returns `None` for versions older than 1.1.
+	pub fn from_raw(ver: u32) -> Option<JniVersion> {
+		JNI_VERSIONS.iter().rev().find(|&&v| v as u32 <= ver).cloned()
+	}
+}
 
 #[derive(Debug, Clone, PartialEq, Eq, Copy)]
 #[repr(C)]
@@ -202,36 +230,36 @@ pub struct JNINativeInterface {
 	pub CallVoidMethodA:    extern "C" fn(env: *mut JNIEnvImpl, obj: jobject, methodID: jmethodID, args: *const jvalue),
 
 	/// these all may throw
-	pub CallNonvirtualObjectMethod:     extern "C" fn(env: *mut JNIEnvImpl, obj: jobject, methodID: jmethodID, ...) -> jobject,
-	pub CallNonvirtualObjectMethodV:    extern "C" fn(env: *mut JNIEnvImpl, obj: jobject, methodID: jmethodID, args: self::Empty) -> jobject,
-	pub CallNonvirtualObjectMethodA:    extern "C" fn(env: *mut JNIEnvImpl, obj: jobject, methodID: jmethodID, args: *const jvalue) -> jobject,
-	pub CallNonvirtualBooleanMethod:    extern "C" fn(env: *mut JNIEnvImpl, obj: jobject, methodID: jmethodID, ...) -> jboolean,
-	pub CallNonvirtualBooleanMethodV:   extern "C" fn(env: *mut JNIEnvImpl, obj: jobject, methodID: jmethodID, args: self::Empty) -> jboolean,
-	pub CallNonvirtualBooleanMethodA:   extern "C" fn(env: *mut JNIEnvImpl, obj: jobject, methodID: jmethodID, args: *const jvalue) -> jboolean,
-	pub CallNonvirtualByteMethod:       extern "C" fn(env: *mut JNIEnvImpl, obj: jobject, methodID: jmethodID, ...) -> jbyte,
-	pub CallNonvirtualByteMethodV:      extern "C" fn(env: *mut JNIEnvImpl, obj: jobject, methodID: jmethodID, args: self::Empty) -> jbyte,
-	pub CallNonvirtualByteMethodA:      extern "C" fn(env: *mut JNIEnvImpl, obj: jobject, methodID: jmethodID, args: *const jvalue) -> jbyte,
-	pub CallNonvirtualCharMethod:       extern "C" fn(env: *mut JNIEnvImpl, obj: jobject, methodID: jmethodID, ...) -> jchar,
-	pub CallNonvirtualCharMethodV:      extern "C" fn(env: *mut JNIEnvImpl, obj: jobject, methodID: jmethodID, args: self::Empty) -> jchar,
-	pub CallNonvirtualCharMethodA:      extern "C" fn(env: *mut JNIEnvImpl, obj: jobject, methodID: jmethodID, args: *const jvalue) -> jchar,
-	pub CallNonvirtualShortMethod:      extern "C" fn(env: *mut JNIEnvImpl, obj: jobject, methodID: jmethodID, ...) -> jshort,
-	pub CallNonvirtualShortMethodV:     extern "C" fn(env: *mut JNIEnvImpl, obj: jobject, methodID: jmethodID, args: self::Empty) -> jshort,
-	pub CallNonvirtualShortMethodA:     extern "C" fn(env: *mut JNIEnvImpl, obj: jobject, methodID: jmethodID, args: *const jvalue) -> jshort,
-	pub CallNonvirtualIntMethod:        extern "C" fn(env: *mut JNIEnvImpl, obj: jobject, methodID: jmethodID, ...) -> jint,
-	pub CallNonvirtualIntMethodV:       extern "C" fn(env: *mut JNIEnvImpl, obj: jobject, methodID: jmethodID, args: self::Empty) -> jint,
-	pub CallNonvirtualIntMethodA:       extern "C" fn(env: *mut JNIEnvImpl, obj: jobject, methodID: jmethodID, args: *const jvalue) -> jint,
-	pub CallNonvirtualLongMethod:       extern "C" fn(env: *mut JNIEnvImpl, obj: jobject, methodID: jmethodID, ...) -> jlong,
-	pub CallNonvirtualLongMethodV:      extern "C" fn(env: *mut JNIEnvImpl, obj: jobject, methodID: jmethodID, args: self::Empty) -> jlong,
-	pub CallNonvirtualLongMethodA:      extern "C" fn(env: *mut JNIEnvImpl, obj: jobject, methodID: jmethodID, args: *const jvalue) -> jlong,
-	pub CallNonvirtualFloatMethod:      extern "C" fn(env: *mut JNIEnvImpl, obj: jobject, methodID: jmethodID, ...) -> jfloat,
-	pub CallNonvirtualFloatMethodV:     extern "C" fn(env: *mut JNIEnvImpl, obj: jobject, methodID: jmethodID, args: self::Empty) -> jfloat,
-	pub CallNonvirtualFloatMethodA:     extern "C" fn(env: *mut JNIEnvImpl, obj: jobject, methodID: jmethodID, args: *const jvalue) -> jfloat,
-	pub CallNonvirtualDoubleMethod:     extern "C" fn(env: *mut JNIEnvImpl, obj: jobject, methodID: jmethodID, ...) -> jdouble,
-	pub CallNonvirtualDoubleMethodV:    extern "C" fn(env: *mut JNIEnvImpl, obj: jobject, methodID: jmethodID, args: self::Empty) -> jdouble,
-	pub CallNonvirtualDoubleMethodA:    extern "C" fn(env: *mut JNIEnvImpl, obj: jobject, methodID: jmethodID, args: *const jvalue) -> jdouble,
-	pub CallNonvirtualVoidMethod:       extern "C" fn(env: *mut JNIEnvImpl, obj: jobject, methodID: jmethodID, ...),
-	pub CallNonvirtualVoidMethodV:      extern "C" fn(env: *mut JNIEnvImpl, obj: jobject, methodID: jmethodID, args: self::Empty),
-	pub CallNonvirtualVoidMethodA:      extern "C" fn(env: *mut JNIEnvImpl, obj: jobject, methodID: jmethodID, args: *const jvalue),
+	pub CallNonvirtualObjectMethod:     extern "C" fn(env: *mut JNIEnvImpl, obj: jobject, clazz: jclass, methodID: jmethodID, ...) -> jobject,
+	pub CallNonvirtualObjectMethodV:    extern "C" fn(env: *mut JNIEnvImpl, obj: jobject, clazz: jclass, methodID: jmethodID, args: self::Empty) -> jobject,
+	pub CallNonvirtualObjectMethodA:    extern "C" fn(env: *mut JNIEnvImpl, obj: jobject, clazz: jclass, methodID: jmethodID, args: *const jvalue) -> jobject,
+	pub CallNonvirtualBooleanMethod:    extern "C" fn(env: *mut JNIEnvImpl, obj: jobject, clazz: jclass, methodID: jmethodID, ...) -> jboolean,
+	pub CallNonvirtualBooleanMethodV:   extern "C" fn(env: *mut JNIEnvImpl, obj: jobject, clazz: jclass, methodID: jmethodID, args: self::Empty) -> jboolean,
+	pub CallNonvirtualBooleanMethodA:   extern "C" fn(env: *mut JNIEnvImpl, obj: jobject, clazz: jclass, methodID: jmethodID, args: *const jvalue) -> jboolean,
+	pub CallNonvirtualByteMethod:       extern "C" fn(env: *mut JNIEnvImpl, obj: jobject, clazz: jclass, methodID: jmethodID, ...) -> jbyte,
+	pub CallNonvirtualByteMethodV:      extern "C" fn(env: *mut JNIEnvImpl, obj: jobject, clazz: jclass, methodID: jmethodID, args: self::Empty) -> jbyte,
+	pub CallNonvirtualByteMethodA:      extern "C" fn(env: *mut JNIEnvImpl, obj: jobject, clazz: jclass, methodID: jmethodID, args: *const jvalue) -> jbyte,
+	pub CallNonvirtualCharMethod:       extern "C" fn(env: *mut JNIEnvImpl, obj: jobject, clazz: jclass, methodID: jmethodID, ...) -> jchar,
+	pub CallNonvirtualCharMethodV:      extern "C" fn(env: *mut JNIEnvImpl, obj: jobject, clazz: jclass, methodID: jmethodID, args: self::Empty) -> jchar,
+	pub CallNonvirtualCharMethodA:      extern "C" fn(env: *mut JNIEnvImpl, obj: jobject, clazz: jclass, methodID: jmethodID, args: *const jvalue) -> jchar,
+	pub CallNonvirtualShortMethod:      extern "C" fn(env: *mut JNIEnvImpl, obj: jobject, clazz: jclass, methodID: jmethodID, ...) -> jshort,
+	pub CallNonvirtualShortMethodV:     extern "C" fn(env: *mut JNIEnvImpl, obj: jobject, clazz: jclass, methodID: jmethodID, args: self::Empty) -> jshort,
+	pub CallNonvirtualShortMethodA:     extern "C" fn(env: *mut JNIEnvImpl, obj: jobject, clazz: jclass, methodID: jmethodID, args: *const jvalue) -> jshort,
+	pub CallNonvirtualIntMethod:        extern "C" fn(env: *mut JNIEnvImpl, obj: jobject, clazz: jclass, methodID: jmethodID, ...) -> jint,
+	pub CallNonvirtualIntMethodV:       extern "C" fn(env: *mut JNIEnvImpl, obj: jobject, clazz: jclass, methodID: jmethodID, args: self::Empty) -> jint,
+	pub CallNonvirtualIntMethodA:       extern "C" fn(env: *mut JNIEnvImpl, obj: jobject, clazz: jclass, methodID: jmethodID, args: *const jvalue) -> jint,
+	pub CallNonvirtualLongMethod:       extern "C" fn(env: *mut JNIEnvImpl, obj: jobject, clazz: jclass, methodID: jmethodID, ...) -> jlong,
+	pub CallNonvirtualLongMethodV:      extern "C" fn(env: *mut JNIEnvImpl, obj: jobject, clazz: jclass, methodID: jmethodID, args: self::Empty) -> jlong,
+	pub CallNonvirtualLongMethodA:      extern "C" fn(env: *mut JNIEnvImpl, obj: jobject, clazz: jclass, methodID: jmethodID, args: *const jvalue) -> jlong,
+	pub CallNonvirtualFloatMethod:      extern "C" fn(env: *mut JNIEnvImpl, obj: jobject, clazz: jclass, methodID: jmethodID, ...) -> jfloat,
+	pub CallNonvirtualFloatMethodV:     extern "C" fn(env: *mut JNIEnvImpl, obj: jobject, clazz: jclass, methodID: jmethodID, args: self::Empty) -> jfloat,
+	pub CallNonvirtualFloatMethodA:     extern "C" fn(env: *mut JNIEnvImpl, obj: jobject, clazz: jclass, methodID: jmethodID, args: *const jvalue) -> jfloat,
+	pub CallNonvirtualDoubleMethod:     extern "C" fn(env: *mut JNIEnvImpl, obj: jobject, clazz: jclass, methodID: jmethodID, ...) -> jdouble,
+	pub CallNonvirtualDoubleMethodV:    extern "C" fn(env: *mut JNIEnvImpl, obj: jobject, clazz: jclass, methodID: jmethodID, args: self::Empty) -> jdouble,
+	pub CallNonvirtualDoubleMethodA:    extern "C" fn(env: *mut JNIEnvImpl, obj: jobject, clazz: jclass, methodID: jmethodID, args: *const jvalue) -> jdouble,
+	pub CallNonvirtualVoidMethod:       extern "C" fn(env: *mut JNIEnvImpl, obj: jobject, clazz: jclass, methodID: jmethodID, ...),
+	pub CallNonvirtualVoidMethodV:      extern "C" fn(env: *mut JNIEnvImpl, obj: jobject, clazz: jclass, methodID: jmethodID, args: self::Empty),
+	pub CallNonvirtualVoidMethodA:      extern "C" fn(env: *mut JNIEnvImpl, obj: jobject, clazz: jclass, methodID: jmethodID, args: *const jvalue),
 
 	/// may throw
 	pub GetFieldID:         extern "C" fn(env: *mut JNIEnvImpl, clazz: jclass, name: *const ::libc::c_char, sig: *const ::libc::c_char) -> jfieldID,
@@ -417,6 +445,7 @@ pub struct JNINativeInterface {
 pub type JNIEnvImpl = *const JNINativeInterface;
 
 
+#[cfg(not(all(unix, feature = "dynamic-jvm")))]
 #[link(name = "jvm")]
 extern "C" {
 	pub fn JNI_CreateJavaVM(vm: *mut *mut JavaVMImpl, env: *mut *mut JNIEnvImpl, args: *mut JavaVMInitArgsImpl) -> JniError;
@@ -424,14 +453,18 @@ extern "C" {
 	pub fn JNI_GetCreatedJavaVMs(vm: *mut *mut JavaVMImpl, bufLen: jsize, nVMs: *mut jsize) -> JniError;
 }
 
-#[allow(dead_code)]
+#[cfg(all(unix, feature = "dynamic-jvm"))]
+pub use super::jvm_library::{JNI_CreateJavaVM, JNI_GetDefaultJavaVMInitArgs, JNI_GetCreatedJavaVMs};
+
+#[repr(C)]
 pub struct JNINativeMethod {
-	name: *mut ::libc::c_char,
-	signature: *mut ::libc::c_char,
-	fnPtr: *mut jvoid
+	pub name: *mut ::libc::c_char,
+	pub signature: *mut ::libc::c_char,
+	pub fnPtr: *mut jvoid
 }
 
-#[derive(Copy, Clone)]
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum jobjectRefType {
 	JNIInvalidRefType = 0,
 	JNILocalRefType = 1,
@@ -453,6 +486,7 @@ pub struct JavaVMInitArgsImpl {
 	pub ignoreUnrecognized: jboolean
 }
 
+#[repr(C)]
 #[derive(Copy, Clone)]
 #[allow(raw_pointer_derive)]
 pub struct JavaVMAttachArgsImpl {
@@ -0,0 +1,187 @@
+//! Counting of JNI references, to find leaks.
+//!
+//! With the `track-refs` feature, every `JavaEnv` counts the local refs
+//! created through it, and global and weak refs are counted for the whole
+//! process.  Local refs still alive when their `JavaEnv` drops are reported,
+//! and so are global and weak refs still alive when the `JavaVM` drops,
+//! with backtraces of where they were created.  The feature also turns on
+//! the check of the type of a reference before it is deleted, which is
+//! otherwise only done in debug builds.
+//!
+//! Without the feature, all of this compiles to nothing.
+
+#[cfg(feature = "track-refs")]
+use ::std::backtrace::Backtrace;
+#[cfg(feature = "track-refs")]
+use ::std::cell::Cell;
+#[cfg(feature = "track-refs")]
+use ::std::collections::BTreeMap;
+#[cfg(feature = "track-refs")]
+use ::std::sync::Mutex;
+
+use super::native::jobject;
+use super::jni::RefType;
+
+/// Counts of the references of one `RefType`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RefCounts {
+	pub created: usize,
+	pub deleted: usize,
+	/// The most references that were alive at the same time.
+	pub high_water: usize,
+}
+
+impl RefCounts {
+	pub fn live(&self) -> usize {
+		self.created.saturating_sub(self.deleted)
+	}
+
+	#[cfg(feature = "track-refs")]
+	fn inc(&mut self) {
+		self.created += 1;
+		if self.live() > self.high_water {
+			self.high_water = self.live();
+		}
+	}
+
+	#[cfg(feature = "track-refs")]
+	fn dec(&mut self) {
+		self.deleted += 1;
+	}
+}
+
+/// Local refs of one `JavaEnv`.
+#[derive(Debug)]
+pub struct LocalRefs {
+	#[cfg(feature = "track-refs")]
+	counts: Cell<RefCounts>,
+}
+
+impl LocalRefs {
+	pub fn new() -> LocalRefs {
+		LocalRefs{
+			#[cfg(feature = "track-refs")]
+			counts: Cell::new(RefCounts::default()),
+		}
+	}
+
+	#[cfg(feature = "track-refs")]
+	pub fn counts(&self) -> RefCounts {
+		self.counts.get()
+	}
+
+	#[cfg(feature = "track-refs")]
+	pub fn created(&self) {
+		let mut counts = self.counts.get();
+		counts.inc();
+		self.counts.set(counts);
+	}
+
+	#[cfg(not(feature = "track-refs"))]
+	pub fn created(&self) {}
+
+	#[cfg(feature = "track-refs")]
+	pub fn deleted(&self) {
+		let mut counts = self.counts.get();
+		counts.dec();
+		self.counts.set(counts);
+	}
+
+	#[cfg(not(feature = "track-refs"))]
+	pub fn deleted(&self) {}
+
+	/// Reports local refs that outlived their `JavaEnv`.
+	#[cfg(feature = "track-refs")]
+	pub fn report(&self) {
+		let counts = self.counts.get();
+		if counts.live() != 0 {
+			eprintln!("JavaEnv dropped with {} live local refs ({} created, {} deleted, at most {} alive at once).",
+				counts.live(), counts.created, counts.deleted, counts.high_water);
+		}
+	}
+
+	#[cfg(not(feature = "track-refs"))]
+	pub fn report(&self) {}
+}
+
+#[cfg(feature = "track-refs")]
+struct GlobalRefs {
+	global: RefCounts,
+	weak: RefCounts,
+	live: BTreeMap<usize, (RefType, Backtrace)>,
+}
+
+#[cfg(feature = "track-refs")]
+static GLOBAL_REFS: Mutex<GlobalRefs> = Mutex::new(GlobalRefs{
+	global: RefCounts{ created: 0, deleted: 0, high_water: 0 },
+	weak: RefCounts{ created: 0, deleted: 0, high_water: 0 },
+	live: BTreeMap::new(),
+});
+
+#[cfg(feature = "track-refs")]
+fn global_refs() -> ::std::sync::MutexGuard<'static, GlobalRefs> {
+	// the counts are still fine if some thread panicked while holding the lock
+	match GLOBAL_REFS.lock() {
+		Ok(refs) => refs,
+		Err(err) => err.into_inner(),
+	}
+}
+
+/// Counts a new global or weak ref and remembers where it was created.
+#[cfg(feature = "track-refs")]
+pub fn global_created(ptr: jobject, typ: RefType) {
+	if ptr == 0 as jobject {
+		return;
+	}
+	let mut refs = global_refs();
+	match typ {
+		RefType::Global => refs.global.inc(),
+		RefType::Weak => refs.weak.inc(),
+		_ => return,
+	}
+	refs.live.insert(ptr as usize, (typ, Backtrace::force_capture()));
+}
+
+#[cfg(not(feature = "track-refs"))]
+pub fn global_created(_ptr: jobject, _typ: RefType) {}
+
+#[cfg(feature = "track-refs")]
+pub fn global_deleted(ptr: jobject, typ: RefType) {
+	let mut refs = global_refs();
+	match typ {
+		RefType::Global => refs.global.dec(),
+		RefType::Weak => refs.weak.dec(),
+		_ => return,
+	}
+	refs.live.remove(&(ptr as usize));
+}
+
+#[cfg(not(feature = "track-refs"))]
+pub fn global_deleted(_ptr: jobject, _typ: RefType) {}
+
+#[cfg(feature = "track-refs")]
+pub fn global_counts(typ: RefType) -> RefCounts {
+	let refs = global_refs();
+	match typ {
+		RefType::Global => refs.global,
+		RefType::Weak => refs.weak,
+		_ => RefCounts::default(),
+	}
+}
+
+/// Reports global and weak refs that are still alive.
+#[cfg(feature = "track-refs")]
+pub fn report_globals() {
+	let refs = global_refs();
+	if refs.live.is_empty() {
+		return;
+	}
+	eprintln!("JavaVM dropped with {} live global refs (at most {} alive at once) and {} live weak refs (at most {} alive at once).",
+		refs.global.live(), refs.global.high_water, refs.weak.live(), refs.weak.high_water);
+	for (ptr, &(typ, ref backtrace)) in refs.live.iter() {
+		eprintln!("{:?} reference {:#x} created at:\n{}", typ, ptr, backtrace);
+	}
+}
+
+#[cfg(not(feature = "track-refs"))]
+pub fn report_globals() {}
@@ -0,0 +1,307 @@
+//! Parsing and building of JNI type descriptors, like `I`,
+//! `[Ljava/lang/String;` or `(ILjava/lang/String;[J)V`.
+
+use ::std::fmt;
+
+use super::jni::*;
+
+/// A Java type, as described by a JNI type descriptor.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JavaType {
+	Boolean,
+	Byte,
+	Char,
+	Short,
+	Int,
+	Long,
+	Float,
+	Double,
+	/// Only valid as a method return type.
+	Void,
+	/// A class, with its name in the internal form, like `java/lang/String`.
+	Class(String),
+	Array(Box<JavaType>),
+	/// A method with its argument types and return type.
+	Method(Vec<JavaType>, Box<JavaType>),
+}
+
+impl JavaType {
+	/// Parses a field or method descriptor.
+	/// Returns `None` if the descriptor is malformed.
+	pub fn parse(sig: &str) -> Option<JavaType> {
+		let (res, rest) = if sig.starts_with("(") {
+			match parse_method(&sig[1..]) {
+				None => return None,
+				Some(v) => v,
+			}
+		} else {
+			match parse_type(sig) {
+				None => return None,
+				Some(v) => v,
+			}
+		};
+
+		if rest.is_empty() {
+			Some(res)
+		} else {
+			None
+		}
+	}
+
+	pub fn class(name: &str) -> JavaType {
+		JavaType::Class(name.to_string())
+	}
+
+	pub fn array(elem: JavaType) -> JavaType {
+		JavaType::Array(Box::new(elem))
+	}
+
+	pub fn method(args: &[JavaType], ret: JavaType) -> JavaType {
+		JavaType::Method(args.to_vec(), Box::new(ret))
+	}
+
+	/// Builds a method type from Rust argument and return types.
+	pub fn method_of<R: JTyped>(args: &[JavaType]) -> JavaType {
+		JavaType::method(args, R::java_type())
+	}
+
+	/// Gets the type for a Rust type.
+	pub fn of<T: JTyped>() -> JavaType {
+		T::java_type()
+	}
+
+	/// Gets the JNI descriptor for this type.
+	pub fn descriptor(&self) -> String {
+		format!("{}", self)
+	}
+
+	pub fn is_primitive(&self) -> bool {
+		match *self {
+			JavaType::Class(_) | JavaType::Array(_) | JavaType::Method(_, _) | JavaType::Void => false,
+			_ => true,
+		}
+	}
+
+	pub fn is_reference(&self) -> bool {
+		match *self {
+			JavaType::Class(_) | JavaType::Array(_) => true,
+			_ => false,
+		}
+	}
+
+	/// The name of the class of a reference type, as `FindClass` takes it,
+	/// like `java/lang/String` or `[I`.
+	pub fn class_name(&self) -> Option<String> {
+		match *self {
+			JavaType::Class(ref name) => Some(name.clone()),
+			JavaType::Array(_) => Some(self.descriptor()),
+			_ => None,
+		}
+	}
+
+	/// Checks if a value of type `other` can be used where `self` is expected.
+	///
+	/// Primitive types, and arrays of them, must match exactly.  Other
+	/// reference types are only checked to be references here: their
+	/// classes are checked with the JVM when calling methods and accessing
+	/// fields.
+	pub fn is_compatible(&self, other: &JavaType) -> bool {
+		match (self, other) {
+			(&JavaType::Array(ref a), &JavaType::Array(ref b)) if a.is_primitive() || b.is_primitive() => a == b,
+			(&JavaType::Array(ref a), &JavaType::Array(ref b)) => a.is_compatible(b),
+			_ if self.is_reference() => other.is_reference(),
+			_ => self == other,
+		}
+	}
+}
+
+impl fmt::Display for JavaType {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			JavaType::Boolean => write!(f, "Z"),
+			JavaType::Byte => write!(f, "B"),
+			JavaType::Char => write!(f, "C"),
+			JavaType::Short => write!(f, "S"),
+			JavaType::Int => write!(f, "I"),
+			JavaType::Long => write!(f, "J"),
+			JavaType::Float => write!(f, "F"),
+			JavaType::Double => write!(f, "D"),
+			JavaType::Void => write!(f, "V"),
+			JavaType::Class(ref name) => write!(f, "L{};", name),
+			JavaType::Array(ref elem) => write!(f, "[{}", elem),
+			JavaType::Method(ref args, ref ret) => {
+				try!(write!(f, "("));
+				for arg in args {
+					try!(write!(f, "{}", arg));
+				}
+				write!(f, "){}", ret)
+			}
+		}
+	}
+}
+
+fn parse_type(sig: &str) -> Option<(JavaType, &str)> {
+	let res = match sig.as_bytes().first() {
+		Some(&b'Z') => JavaType::Boolean,
+		Some(&b'B') => JavaType::Byte,
+		Some(&b'C') => JavaType::Char,
+		Some(&b'S') => JavaType::Short,
+		Some(&b'I') => JavaType::Int,
+		Some(&b'J') => JavaType::Long,
+		Some(&b'F') => JavaType::Float,
+		Some(&b'D') => JavaType::Double,
+		Some(&b'L') => {
+			return match sig.find(';') {
+				None | Some(1) => None,
+				Some(i) => Some((JavaType::Class(sig[1..i].to_string()), &sig[i + 1..])),
+			}
+		}
+		Some(&b'[') => {
+			return match parse_type(&sig[1..]) {
+				None => None,
+				Some((elem, rest)) => Some((JavaType::array(elem), rest)),
+			}
+		}
+		_ => return None,
+	};
+	Some((res, &sig[1..]))
+}
+
+fn parse_method(sig: &str) -> Option<(JavaType, &str)> {
+	let mut args = vec![];
+	let mut rest = sig;
+	while !rest.starts_with(")") {
+		match parse_type(rest) {
+			None => return None,
+			Some((arg, r)) => {
+				args.push(arg);
+				rest = r;
+			}
+		}
+	}
+	rest = &rest[1..];
+
+	if rest.starts_with("V") {
+		return Some((JavaType::Method(args, Box::new(JavaType::Void)), &rest[1..]));
+	}
+	match parse_type(rest) {
+		None => None,
+		Some((ret, r)) => Some((JavaType::Method(args, Box::new(ret)), r)),
+	}
+}
+
+/// A Rust type that has a corresponding Java type.
+pub trait JTyped {
+	fn java_type() -> JavaType;
+}
+
+macro_rules! impl_jtyped(
+	($rtype:ty, $jtype:expr) => (
+		impl JTyped for $rtype {
+			fn java_type() -> JavaType {
+				$jtype
+			}
+		}
+	);
+);
+
+macro_rules! impl_jtyped_class(
+	($cls:ident, $name:expr) => (
+		impl<'a> JTyped for $cls<'a> {
+			fn java_type() -> JavaType {
+				JavaType::class($name)
+			}
+		}
+	);
+);
+
+impl_jtyped!((), JavaType::Void);
+impl_jtyped!(bool, JavaType::Boolean);
+impl_jtyped!(u8, JavaType::Byte);
+impl_jtyped!(char, JavaType::Char);
+impl_jtyped!(i16, JavaType::Short);
+impl_jtyped!(i32, JavaType::Int);
+impl_jtyped!(i64, JavaType::Long);
+impl_jtyped!(f32, JavaType::Float);
+impl_jtyped!(f64, JavaType::Double);
+
+impl_jtyped_class!(JavaObject, "java/lang/Object");
+impl_jtyped_class!(JavaClass, "java/lang/Class");
+impl_jtyped_class!(JavaThrowable, "java/lang/Throwable");
+impl_jtyped_class!(JavaString, "java/lang/String");
+impl_jtyped_class!(JavaDirectByteBuffer, "java/nio/ByteBuffer");
+
+impl<'a, T: 'a + JArrayElem<'a> + JTyped> JTyped for JavaArray<'a, T> {
+	fn java_type() -> JavaType {
+		JavaType::array(T::java_type())
+	}
+}
+
+impl<T: JTyped> JTyped for Option<T> {
+	fn java_type() -> JavaType {
+		T::java_type()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_parse() {
+		assert!(JavaType::parse("I") == Some(JavaType::Int));
+		assert!(JavaType::parse("Ljava/lang/String;") == Some(JavaType::class("java/lang/String")));
+		assert!(JavaType::parse("[[J") == Some(JavaType::array(JavaType::array(JavaType::Long))));
+		assert!(JavaType::parse("()V") == Some(JavaType::method(&[], JavaType::Void)));
+		assert!(JavaType::parse("(ILjava/lang/String;[J)V") == Some(JavaType::method(
+			&[JavaType::Int, JavaType::class("java/lang/String"), JavaType::array(JavaType::Long)],
+			JavaType::Void
+		)));
+		assert!(JavaType::parse("([Ljava/lang/Object;Z)[B") == Some(JavaType::method(
+			&[JavaType::array(JavaType::class("java/lang/Object")), JavaType::Boolean],
+			JavaType::array(JavaType::Byte)
+		)));
+	}
+
+	#[test]
+	fn test_parse_invalid() {
+		for s in &["", "V", "Q", "II", "L;", "Ljava/lang/String", "[", "[V", "(", "(I", "(I)", "(V)V", "()VI", "()(I)V"] {
+			assert!(JavaType::parse(s) == None);
+		}
+	}
+
+	#[test]
+	fn test_descriptor() {
+		for s in &["Z", "[C", "Ljava/lang/Object;", "(ILjava/lang/String;[J)V", "([[DS)Ljava/lang/Class;"] {
+			assert!(JavaType::parse(s).unwrap().descriptor() == *s);
+		}
+	}
+
+	#[test]
+	fn test_JTyped() {
+		assert!(JavaType::of::<i32>() == JavaType::Int);
+		assert!(JavaType::of::<JavaString>().descriptor() == "Ljava/lang/String;");
+		assert!(JavaType::of::<JavaArray<i64>>().descriptor() == "[J");
+		assert!(JavaType::of::<JavaArray<JavaArray<JavaString>>>().descriptor() == "[[Ljava/lang/String;");
+		assert!(JavaType::method_of::<bool>(&[JavaType::of::<JavaObject>(), JavaType::of::<char>()]).descriptor() == "(Ljava/lang/Object;C)Z");
+	}
+
+	#[test]
+	fn test_is_compatible() {
+		assert!(JavaType::Int.is_compatible(&JavaType::Int));
+		assert!(!JavaType::Int.is_compatible(&JavaType::Long));
+		assert!(JavaType::class("java/lang/Object").is_compatible(&JavaType::array(JavaType::Int)));
+		assert!(!JavaType::class("java/lang/Object").is_compatible(&JavaType::Int));
+		assert!(!JavaType::array(JavaType::Int).is_compatible(&JavaType::array(JavaType::Long)));
+		assert!(!JavaType::array(JavaType::Byte).is_compatible(&JavaType::array(JavaType::class("java/lang/Byte"))));
+		assert!(JavaType::array(JavaType::class("java/lang/Object")).is_compatible(&JavaType::array(JavaType::class("java/lang/String"))));
+	}
+
+	#[test]
+	fn test_class_name() {
+		assert!(JavaType::class("java/lang/String").class_name() == Some("java/lang/String".to_string()));
+		assert!(JavaType::array(JavaType::class("java/lang/String")).class_name() == Some("[Ljava/lang/String;".to_string()));
+		assert!(JavaType::array(JavaType::Int).class_name() == Some("[I".to_string()));
+		assert!(JavaType::Int.class_name() == None);
+	}
+}
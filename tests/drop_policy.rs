@@ -0,0 +1,17 @@
+extern crate jni;
+
+use jni::*;
+
+// a process can only create one JVM, so this test has its own
+#[test]
+fn test_leak() {
+	let args = JavaVMInitArgs::new(JniVersion::JNI_VERSION_1_8, &[], false);
+	let mut jvm = JavaVM::new(args).unwrap();
+	jvm.set_drop_policy(DropPolicy::Leak);
+	drop(jvm);
+
+	// the JVM is still running, and can be used
+	let jvm = JavaVM::current().unwrap();
+	let (env, cap) = jvm.get_env().unwrap();
+	assert!(JavaClass::find(&env, "java/lang/String", cap).is_ok());
+}
@@ -1,31 +1,83 @@
 #![allow(dead_code)]
 extern crate libc;
+#[macro_use]
 extern crate jni;
 
 use jni::*;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 
-#[test]
-fn test() {
-	assert!(!mytest().is_err());
+static LOADED: AtomicBool = AtomicBool::new(false);
+static UNLOADED: AtomicBool = AtomicBool::new(false);
+
+jni_onload!(|env: &JavaEnv, cap| {
+	assert!(JavaClass::find(env, "java/lang/String", cap).is_ok());
+	LOADED.store(true, Ordering::SeqCst);
+	JniVersion::JNI_VERSION_1_6
+});
+
+jni_onunload!(|_env: &JavaEnv, _cap| {
+	UNLOADED.store(true, Ordering::SeqCst);
+});
+
+#[jni_export(class = "com.acme.Foo_Bar")]
+fn add_ints<'a>(_env: &'a JavaEnv<'a>, _cls: JavaClass<'a>, a: i32, b: i32) -> i32 {
+	a + b
 }
 
-fn mytest() -> Result<(),jni::Exception> {
-	let opt1 = JavaVMOption::new("-Xcheck:jni");
-	println!("Opt is {:?}", opt1);
+#[jni_export(class = "com.acme.Foo", panic = "java.lang.IllegalStateException")]
+fn fail<'a>(_env: &'a JavaEnv<'a>, _cls: JavaClass<'a>) -> Option<JavaString<'a>> {
+	panic!("not implemented")
+}
 
-	let opt2 = JavaVMOption::new("-verbose:jni");
-	println!("Opt is {:?}", opt2);
+#[jni_export(class = "com.acme.Foo", name = "greet")]
+fn greet_impl<'a>(env: &'a JavaEnv<'a>, _this: JavaObject<'a>, cap: Capability, name: Option<JavaString<'a>>) -> JniResult<JavaString<'a>> {
+	let name = match name {
+		Some(name) => name.to_str(&cap).unwrap(),
+		None => "nobody".to_string(),
+	};
+	JavaString::new(env, &format!("Hello, {}!", name), cap)
+}
+
+#[jni_export(class = "com.acme.Foo")]
+fn shout<'a>(env: &'a JavaEnv<'a>, _cls: JavaClass<'a>, cap: Capability, text: JavaString<'a>) -> JniResult<JavaString<'a>> {
+	let (res, cap) = try!(env.with_local_frame::<JavaString<'static>, _>(4, cap, |env, cap| {
+		let (upper, cap) = try!(JavaString::new(env, &text.to_str(&cap).unwrap().to_uppercase(), cap));
+		Ok((Some(upper), cap))
+	}));
+	Ok((res.unwrap(), cap))
+}
 
+#[test]
+fn test() {
 	let args = JavaVMInitArgs::new(
 		jni::JniVersion::JNI_VERSION_1_4,
-		&[/*opt1, JavaVMOption::new("-verbose:jni")*/][..],
+		&[/*JavaVMOption::new("-Xcheck:jni"), JavaVMOption::new("-verbose:jni")*/][..],
 		false,
 	);
 	println!("Args are {:?}", args);
 
-	let jvm = JavaVM::new(args).unwrap();
+	let mut jvm = JavaVM::new(args).unwrap();
 	println!("Jvm is {:?}", jvm);
 
+	assert!(!mytest(&jvm).is_err());
+
+	// this thread created the JVM, so it is attached, and must not keep
+	// the destroying thread waiting
+	jvm.set_drop_policy(DropPolicy::DestroyTimeout(Duration::from_secs(30)));
+	let start = Instant::now();
+	drop(jvm);
+	assert!(start.elapsed() < Duration::from_secs(30));
+	assert!(JavaVM::current().is_none());
+}
+
+fn mytest(jvm: &JavaVM) -> Result<(),jni::Exception> {
+	let opt1 = JavaVMOption::new("-Xcheck:jni");
+	println!("Opt is {:?}", opt1);
+
+	let opt2 = JavaVMOption::new("-verbose:jni");
+	println!("Opt is {:?}", opt2);
+
 	let (env, cap) = jvm.get_env().unwrap();
 	println!("Env is {:?}", env);
 	println!("Env version is {:?}", env.version(&cap));
@@ -56,14 +108,51 @@ fn mytest() -> Result<(),jni::Exception> {
 
 	println!("st[2:7] == {:?}", st.region(2, 5, cap));
 
+	// `on_load` fails if the handler leaves an exception pending
+	let version = unsafe {
+		JavaVM::on_load(jvm.ptr(), |env, cap| {
+			let _ = JavaClass::find(env, "no/such/Class", cap);
+			JniVersion::JNI_VERSION_1_6
+		})
+	};
+	assert!(version == jni::native::JniError::JNI_ERR as jni::native::jint);
+	let exn = JavaThrowable::check(&env).err().unwrap();
+	let _ = JavaThrowable::clear(&env, exn);
+
 	let cap = JavaThrowable::check(&env).unwrap();
 
+	let version = unsafe { JNI_OnLoad(jvm.ptr(), 0 as *mut jni::native::jvoid) };
+	assert!(version == JniVersion::JNI_VERSION_1_6 as jni::native::jint);
+	assert!(LOADED.load(Ordering::SeqCst));
+	unsafe { JNI_OnUnload(jvm.ptr(), 0 as *mut jni::native::jvoid) };
+	assert!(UNLOADED.load(Ordering::SeqCst));
+
+	let sum = Java_com_acme_Foo_1Bar_add_1ints(unsafe { env.ptr() }, cls.get_obj(), 2, 3);
+	assert!(sum == 5);
+	let greeting = Java_com_acme_Foo_greet(unsafe { env.ptr() }, st.get_obj(), st.get_obj());
+	let greeting: JavaString = JObject::from(&env, greeting).unwrap();
+	assert!(greeting.to_str(&cap).unwrap() == "Hello, Hello, world!!");
+	let greeting = Java_com_acme_Foo_greet(unsafe { env.ptr() }, st.get_obj(), 0 as jni::native::jobject);
+	let greeting: JavaString = JObject::from(&env, greeting).unwrap();
+	assert!(greeting.to_str(&cap).unwrap() == "Hello, nobody!");
+
+	let shouted = Java_com_acme_Foo_shout(unsafe { env.ptr() }, cls.get_obj(), st.get_obj());
+	let shouted: JavaString = JObject::from(&env, shouted).unwrap();
+	assert!(shouted.to_str(&cap).unwrap() == "HELLO, WORLD!");
+
+	let res = Java_com_acme_Foo_fail(unsafe { env.ptr() }, cls.get_obj());
+	assert!(res == 0 as jni::native::jobject);
+	let (ex, exn) = JavaThrowable::occured(&env).err().unwrap();
+	let cap = JavaThrowable::clear(&env, exn);
+	let (ise, cap) = JavaClass::find(&env, "java/lang/IllegalStateException", cap).unwrap();
+	assert!(ex.is_instance_of(&ise, &cap));
+
 	let (gst, cap) = try!(st.global(cap));
-	let (wgst, cap) = try!(gst.weak(cap));
-	let (wst, cap) = try!(st.weak(cap));
-	println!("Wst is null: {:?}", wst.is_null(&cap));
+	let (wgst, cap): (WeakRef<JavaString<'static>>, _) = try!(gst.weak(cap));
+	let (wst, cap): (WeakRef<JavaString<'static>>, _) = try!(st.weak(cap));
+	println!("Wst is null: {:?}", wst.upgrade(&env, &cap).is_none());
 	println!("{:?} {:?} {:?} {:?} {:?}", st, gst, wgst, wst, wgst);
-	println!("Wst is null: {:?}", wst.is_null(&cap));
+	println!("Wst is null: {:?}", wst.upgrade(&env, &cap).is_none());
 
 	Ok(())
 }